@@ -0,0 +1,122 @@
+use std::f32;
+use std::time::Instant;
+
+use ray::*;
+use hitable::*;
+use camera::*;
+use utils::*;
+use sampler::*;
+use output::Framebuffer;
+
+const BUILDERS: [BvhBuilder; 3] = [BvhBuilder::Median, BvhBuilder::Sah, BvhBuilder::Flat];
+
+// Times every bvh builder on the same set of rays: one camera ray per pixel
+// and sample, plus a diffuse bounce from wherever those hit, so both
+// coherent and incoherent traversal are covered. Rays are made one row at a
+// time and only the intersection work is timed, shading and ray generation
+// happen outside the clock. Hit counts should agree between builders.
+pub fn bench_bvh(camera: &Camera, hitables: &[Hitable], nx: u32, ny: u32, ns: u32, seed: u64) {
+    let reference = BvhBuilder::Flat.build(hitables.to_vec(), 0.0, 1.0);
+    let mut worlds = Vec::with_capacity(BUILDERS.len());
+    for builder in BUILDERS.iter() {
+        let build_start = Instant::now();
+        let world = builder.build(hitables.to_vec(), 0.0, 1.0);
+        worlds.push((world, build_start.elapsed().as_secs_f64(), 0.0, 0usize));
+    }
+
+    let mut rays = Vec::with_capacity(2 * nx as usize * ns as usize);
+    let mut ray_count = 0usize;
+    for j in 0..ny {
+        rays.clear();
+        for i in 0..nx {
+            for s in 0..ns {
+                let pixel = u64::from(j) * u64::from(nx) + u64::from(i);
+                let mut sampler = Sampler::new(SamplerKind::Random, seed, ns, pixel, s);
+                let (du, dv) = sampler.next_2d();
+                let u = (i as f32 + du) / nx as f32;
+                let v = (j as f32 + dv) / ny as f32;
+                let ray = camera.get_ray(u, v, &mut sampler);
+                let bounce = reference.hit(&ray, 0.001, f32::MAX).map(|rec| {
+                    let target = rec.normal + random_in_unit_sphere(&mut sampler);
+                    Ray::new(rec.p, target, ray.time())
+                });
+                rays.push(ray);
+                if let Some(bounce) = bounce {
+                    rays.push(bounce);
+                }
+            }
+        }
+        ray_count += rays.len();
+        for &mut (ref world, _, ref mut trace_time, ref mut hits) in &mut worlds {
+            let trace_start = Instant::now();
+            for ray in &rays {
+                if world.hit(ray, 0.001, f32::MAX).is_some() {
+                    *hits += 1;
+                }
+            }
+            *trace_time += trace_start.elapsed().as_secs_f64();
+        }
+    }
+    println!("Traced {} rays through every bvh", ray_count);
+
+    let baseline_time = worlds[0].2;
+    for (builder, &(_, build_time, trace_time, hits)) in BUILDERS.iter().zip(&worlds) {
+        println!(
+            "{:>6}: build {:.3}s, trace {:.3}s, {:.1} krays/s, {} hits, {:.2}x",
+            builder.name(),
+            build_time,
+            trace_time,
+            ray_count as f64 / trace_time / 1e3,
+            hits,
+            baseline_time / trace_time
+        );
+    }
+}
+
+// Renders the scene with every sampler at doubling sample counts up to ns and
+// prints the root mean square error against a reference with 16 times as many
+// random samples from another seed.
+pub fn bench_samplers<F>(render: F, ns: u32, seed: u64)
+where
+    F: Fn(SamplerKind, u32, u64) -> Framebuffer,
+{
+    let reference_start = Instant::now();
+    let reference = render(SamplerKind::Random, 16 * ns, seed.wrapping_add(1));
+    println!(
+        "Rendered the reference at {} spp in {:.1}s",
+        16 * ns,
+        reference_start.elapsed().as_secs_f64()
+    );
+    let mut counts = Vec::new();
+    let mut samples = 1;
+    while samples <= ns {
+        counts.push(samples);
+        samples *= 2;
+    }
+    print!("{:>10}", "spp");
+    for kind in SAMPLER_KINDS.iter() {
+        print!("{:>12}", kind.name());
+    }
+    println!();
+    for &samples in &counts {
+        print!("{:>10}", samples);
+        for &kind in SAMPLER_KINDS.iter() {
+            let image = render(kind, samples, seed);
+            print!("{:>12.5}", rmse(&image, &reference));
+        }
+        println!();
+    }
+}
+
+fn rmse(image: &Framebuffer, reference: &Framebuffer) -> f64 {
+    let sum: f64 = image
+        .pixels()
+        .iter()
+        .zip(reference.pixels())
+        .map(|(a, b)| {
+            let d = *a - *b;
+            f64::from(d.x() * d.x() + d.y() * d.y() + d.z() * d.z())
+        })
+        .sum();
+    (sum / (3 * image.pixels().len()) as f64).sqrt()
+}
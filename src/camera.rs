@@ -1,9 +1,9 @@
 use std::f32::*;
-use rand::{thread_rng, Rng};
 
 use vector::*;
 use ray::*;
 use utils::*;
+use sampler::Sampler;
 
 pub struct Camera {
     origin: Vec3,
@@ -53,11 +53,10 @@ impl Camera {
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let mut rng = thread_rng();
-        let rd = random_in_unit_sphere() * self.lens_radius;
-        let offset = self.u * rd.x() + self.v * rd.y();
-        let time = self.time0 + rng.gen::<f32>() * (self.time1 - self.time0);
+    pub fn get_ray(&self, s: f32, t: f32, sampler: &mut Sampler) -> Ray {
+        let (x, y) = sample_unit_disk(sampler.next_2d());
+        let offset = self.u * (x * self.lens_radius) + self.v * (y * self.lens_radius);
+        let time = self.time0 + sampler.next_1d() * (self.time1 - self.time0);
         Ray::new(
             self.origin + offset,
             self.lower_left_corner + self.horizontal * s + self.vertical * t - self.origin - offset,
@@ -0,0 +1,303 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+use vector::Vec3;
+use render::*;
+use output::write_atomically;
+
+// A checkpoint starts with a few lines of text: a version line, the settings
+// the render was started with and the pixel count. Every film pixel follows
+// as its three sums, its sum of squares, its sample count, its three filter
+// weighted sums and its sum of weights, all little endian. The sums are
+// stored bit for bit, so a resumed render ends up exactly like one that was
+// never interrupted.
+const HEADER: &str = "rust-ray-tracer checkpoint 3";
+const PIXEL_BYTES: usize = 36;
+
+pub fn save_checkpoint(path: &Path, settings: &str, film: &Film) -> io::Result<()> {
+    write_atomically(path, |out| {
+        writeln!(out, "{}", HEADER)?;
+        writeln!(out, "settings {}", settings)?;
+        writeln!(out, "pixels {}", film.pixels().len())?;
+        for pixel in film.pixels() {
+            out.write_all(&pixel.sum.x().to_le_bytes())?;
+            out.write_all(&pixel.sum.y().to_le_bytes())?;
+            out.write_all(&pixel.sum.z().to_le_bytes())?;
+            out.write_all(&pixel.squares.to_le_bytes())?;
+            out.write_all(&pixel.samples.to_le_bytes())?;
+            out.write_all(&pixel.weighted.x().to_le_bytes())?;
+            out.write_all(&pixel.weighted.y().to_le_bytes())?;
+            out.write_all(&pixel.weighted.z().to_le_bytes())?;
+            out.write_all(&pixel.weight.to_le_bytes())?;
+        }
+        Ok(())
+    })
+}
+
+// Reads a checkpoint back, refusing ones made with other settings since
+// continuing them would mix two different renders.
+pub fn load_checkpoint(path: &Path, settings: &str, region: &Region) -> Result<Film, String> {
+    let file = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let mut reader = BufReader::new(file);
+    let error = |message: String| format!("{}: {}", path.display(), message);
+    if read_line(&mut reader).map_err(&error)? != HEADER {
+        return Err(error("not a checkpoint".to_string()));
+    }
+    let saved_settings = field(&mut reader, "settings").map_err(&error)?;
+    if saved_settings != settings {
+        return Err(error(format!(
+            "checkpoint was made with different settings\n  checkpoint: {}\n  now:        {}",
+            saved_settings, settings
+        )));
+    }
+    let count = number(&mut reader, "pixels").map_err(&error)? as usize;
+    let mut data = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .map_err(|err| error(err.to_string()))?;
+    if data.len() != count * PIXEL_BYTES {
+        return Err(error("checkpoint is truncated".to_string()));
+    }
+    let pixels = data
+        .chunks(PIXEL_BYTES)
+        .map(|chunk| {
+            let word = |index: usize| {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(&chunk[index * 4..index * 4 + 4]);
+                bytes
+            };
+            FilmPixel {
+                sum: Vec3(
+                    f32::from_le_bytes(word(0)),
+                    f32::from_le_bytes(word(1)),
+                    f32::from_le_bytes(word(2)),
+                ),
+                squares: f32::from_le_bytes(word(3)),
+                samples: u32::from_le_bytes(word(4)),
+                weighted: Vec3(
+                    f32::from_le_bytes(word(5)),
+                    f32::from_le_bytes(word(6)),
+                    f32::from_le_bytes(word(7)),
+                ),
+                weight: f32::from_le_bytes(word(8)),
+            }
+        })
+        .collect();
+    Film::from_pixels(region, pixels)
+        .ok_or_else(|| error("checkpoint doesn't match the image size".to_string()))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, String> {
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|err| err.to_string())?;
+    Ok(line.trim_end_matches('\n').to_string())
+}
+
+fn field<R: BufRead>(reader: &mut R, name: &str) -> Result<String, String> {
+    let line = read_line(reader)?;
+    match line.split_once(' ') {
+        Some((key, value)) if key == name => Ok(value.to_string()),
+        _ => Err(format!("expected `{}` in the checkpoint header", name)),
+    }
+}
+
+fn number<R: BufRead>(reader: &mut R, name: &str) -> Result<u32, String> {
+    let value = field(reader, name)?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid {} `{}` in the checkpoint header", name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use filter::{Filter, FilterKind};
+    use hitable::BvhBuilder;
+    use integrator::{Integrator, Roulette};
+    use light::LightList;
+    use sampler::SamplerKind;
+    use utils::cornell_box;
+
+    const SETTINGS: &str = "24x16 pixels, 8 samples, seed 3";
+
+    fn region() -> Region {
+        Region {
+            x: 0,
+            y: 0,
+            width: 3,
+            height: 2,
+        }
+    }
+
+    // Values a float text format would round, to check the bits survive.
+    fn film() -> Film {
+        let pixels = (0..6)
+            .map(|i| {
+                let f = i as f32;
+                FilmPixel {
+                    sum: Vec3(0.1 * f, 1e-30 + f, f32::MAX / (f + 1.0)),
+                    squares: 1.0 / 3.0 + f,
+                    samples: 7 * i,
+                    weighted: Vec3(-0.2 * f, f32::MIN_POSITIVE, 2.5e7 * f),
+                    weight: 0.7 + f,
+                }
+            })
+            .collect();
+        Film::from_pixels(&region(), pixels).unwrap()
+    }
+
+    fn scratch_file(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("checkpoint-test-{}-{}", name, std::process::id()))
+    }
+
+    fn bits(pixel: &FilmPixel) -> [u32; 9] {
+        [
+            pixel.sum.x().to_bits(),
+            pixel.sum.y().to_bits(),
+            pixel.sum.z().to_bits(),
+            pixel.squares.to_bits(),
+            pixel.samples,
+            pixel.weighted.x().to_bits(),
+            pixel.weighted.y().to_bits(),
+            pixel.weighted.z().to_bits(),
+            pixel.weight.to_bits(),
+        ]
+    }
+
+    #[test]
+    fn round_trip_keeps_every_bit() {
+        let path = scratch_file("round-trip");
+        let film = film();
+        save_checkpoint(&path, SETTINGS, &film).unwrap();
+        let loaded = load_checkpoint(&path, SETTINGS, &region());
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.pixels().len(), film.pixels().len());
+        for (saved, loaded) in film.pixels().iter().zip(loaded.pixels()) {
+            assert_eq!(bits(saved), bits(loaded));
+        }
+    }
+
+    #[test]
+    fn rejects_other_settings_and_sizes() {
+        let path = scratch_file("mismatch");
+        save_checkpoint(&path, SETTINGS, &film()).unwrap();
+        let other_settings = load_checkpoint(&path, "24x16 pixels, 9 samples, seed 3", &region());
+        let other_region = Region {
+            width: 2,
+            ..region()
+        };
+        let other_size = load_checkpoint(&path, SETTINGS, &other_region);
+        fs::remove_file(&path).unwrap();
+        let message = other_settings.err().unwrap();
+        assert!(
+            message.ends_with(
+                "checkpoint was made with different settings\n  \
+                 checkpoint: 24x16 pixels, 8 samples, seed 3\n  \
+                 now:        24x16 pixels, 9 samples, seed 3"
+            ),
+            "{}",
+            message
+        );
+        let message = other_size.err().unwrap();
+        assert!(
+            message.ends_with("checkpoint doesn't match the image size"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let path = scratch_file("damaged");
+        save_checkpoint(&path, SETTINGS, &film()).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        data.pop();
+        fs::write(&path, &data).unwrap();
+        let truncated = load_checkpoint(&path, SETTINGS, &region());
+        data[0] = b'R';
+        fs::write(&path, &data).unwrap();
+        let wrong_header = load_checkpoint(&path, SETTINGS, &region());
+        fs::remove_file(&path).unwrap();
+        let message = truncated.err().unwrap();
+        assert!(message.ends_with("checkpoint is truncated"), "{}", message);
+        let message = wrong_header.err().unwrap();
+        assert!(message.ends_with("not a checkpoint"), "{}", message);
+    }
+
+    // Renders every pass of the budget, saving a checkpoint after the second
+    // one, then resumes from that checkpoint. Both films must match bit for
+    // bit.
+    fn check_resume(name: &str, adaptive: Option<Adaptive>) {
+        let settings = RenderSettings {
+            width: 12,
+            height: 12,
+            samples: 8,
+            max_depth: 8,
+            roulette: Roulette {
+                depth: 3,
+                min_survival: 0.05,
+            },
+            integrator: Integrator::Mis,
+            sampler: SamplerKind::Sobol,
+            seed: 5,
+            region: Region::full(12, 12),
+            tile_size: 4,
+            tile_order: TileOrder::Spiral,
+            adaptive,
+            filter: Filter {
+                kind: FilterKind::Mitchell,
+                radius: FilterKind::Mitchell.default_radius(),
+            },
+            indirect_clamp: None,
+            debug_invalid: false,
+        };
+        let (camera, hitables) = cornell_box(settings.width, settings.height);
+        let world = BvhBuilder::Flat.build(hitables, 0.0, 1.0);
+        let lights = LightList::new(&world);
+        let renderer = Renderer::new(&camera, &world, &lights, settings);
+        let progressive = Progressive {
+            pass_samples: 2,
+            snapshot_passes: 2,
+            snapshot_seconds: 0.0,
+        };
+        let path = scratch_file(name);
+        let mut saved = false;
+        let straight = progressive.render(&renderer, Film::new(&settings.region), |film| {
+            if !saved {
+                save_checkpoint(&path, SETTINGS, film).unwrap();
+                saved = true;
+            }
+        });
+        let film = load_checkpoint(&path, SETTINGS, &settings.region);
+        fs::remove_file(&path).unwrap();
+        let film = film.unwrap();
+        assert!(film.spent() > 0 && film.spent() < straight.spent());
+        let resumed = progressive.render(&renderer, film, |_| {});
+        assert_eq!(resumed.spent(), straight.spent());
+        for (straight, resumed) in straight.pixels().iter().zip(resumed.pixels()) {
+            assert_eq!(bits(straight), bits(resumed));
+        }
+    }
+
+    #[test]
+    fn resumed_render_matches_an_uninterrupted_one() {
+        check_resume("resume", None);
+    }
+
+    #[test]
+    fn resumed_adaptive_render_matches_an_uninterrupted_one() {
+        check_resume(
+            "resume-adaptive",
+            Some(Adaptive {
+                min_samples: 2,
+                max_samples: 32,
+                threshold: 0.05,
+            }),
+        );
+    }
+}
@@ -0,0 +1,540 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use hitable::BvhBuilder;
+use integrator::{Integrator, Roulette};
+use sampler::SamplerKind;
+use render::{Adaptive, Progressive, Region, TileOrder};
+use filter::{Filter, FilterKind};
+use output::{DisplayTransform, ToneMap};
+use utils::BUILTIN_SCENES;
+
+pub enum SceneSource {
+    Builtin(String),
+    File(PathBuf),
+}
+
+pub struct Options {
+    pub output: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub samples: u32,
+    pub max_depth: u32,
+    pub roulette: Roulette,
+    pub progressive: Progressive,
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+    pub crop: Option<Region>,
+    pub checkpoint: Option<PathBuf>,
+    pub resume: bool,
+    pub adaptive: Option<Adaptive>,
+    pub heatmap: Option<PathBuf>,
+    pub filter: Filter,
+    pub display: DisplayTransform,
+    pub indirect_clamp: Option<f32>,
+    pub debug_invalid: bool,
+    pub threads: usize,
+    pub seed: u64,
+    pub scene: SceneSource,
+    pub bvh: BvhBuilder,
+    pub integrator: Integrator,
+    pub sampler: SamplerKind,
+    pub bench_bvh: bool,
+    pub bench_samplers: bool,
+    pub help: bool,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            output: PathBuf::from("picture.ppm"),
+            width: 600,
+            height: 300,
+            samples: 500,
+            max_depth: 50,
+            roulette: Roulette {
+                depth: 5,
+                min_survival: 0.05,
+            },
+            progressive: Progressive {
+                pass_samples: 0,
+                snapshot_passes: 0,
+                snapshot_seconds: 0.0,
+            },
+            tile_size: 32,
+            tile_order: TileOrder::Spiral,
+            crop: None,
+            checkpoint: None,
+            resume: false,
+            adaptive: None,
+            heatmap: None,
+            filter: Filter {
+                kind: FilterKind::Box,
+                radius: 0.0,
+            },
+            display: DisplayTransform::new(),
+            indirect_clamp: None,
+            debug_invalid: false,
+            threads: 0,
+            seed: 0,
+            scene: SceneSource::Builtin("cornell_box".to_string()),
+            bvh: BvhBuilder::Flat,
+            integrator: Integrator::Mis,
+            sampler: SamplerKind::Sobol,
+            bench_bvh: false,
+            bench_samplers: false,
+            help: false,
+        }
+    }
+
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Options, String> {
+        let mut options = Options::new();
+        let mut args = args;
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.find('=') {
+                Some(index) if arg.starts_with("--") => {
+                    (arg[..index].to_string(), Some(arg[index + 1..].to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let mut value = || -> Result<String, String> {
+                match inline_value.clone() {
+                    Some(value) => Ok(value),
+                    None => args
+                        .next()
+                        .ok_or_else(|| format!("missing value for `{}`", flag)),
+                }
+            };
+            match flag.as_str() {
+                "-h" | "--help" => options.help = true,
+                "-o" | "--output" => options.output = PathBuf::from(value()?),
+                "-W" | "--width" => options.width = parse_number(&flag, &value()?)?,
+                "-H" | "--height" => options.height = parse_number(&flag, &value()?)?,
+                "-s" | "--samples" => options.samples = parse_number(&flag, &value()?)?,
+                "-d" | "--max-depth" => options.max_depth = parse_number(&flag, &value()?)?,
+                "--roulette-depth" => {
+                    options.roulette.depth = parse_number(&flag, &value()?)?
+                }
+                "--roulette-min" => {
+                    options.roulette.min_survival = parse_number(&flag, &value()?)?
+                }
+                "--clamp-indirect" => {
+                    options.indirect_clamp = Some(parse_number(&flag, &value()?)?)
+                }
+                "--debug-invalid" => options.debug_invalid = true,
+                "--pass-samples" => {
+                    options.progressive.pass_samples = parse_number(&flag, &value()?)?
+                }
+                "--snapshot-passes" => {
+                    options.progressive.snapshot_passes = parse_number(&flag, &value()?)?
+                }
+                "--snapshot-seconds" => {
+                    options.progressive.snapshot_seconds = parse_number(&flag, &value()?)?
+                }
+                "--adaptive" => {
+                    let threshold = parse_number(&flag, &value()?)?;
+                    adaptive(&mut options).threshold = threshold;
+                }
+                "--min-samples" => {
+                    let min_samples = parse_number(&flag, &value()?)?;
+                    adaptive(&mut options).min_samples = min_samples;
+                }
+                "--max-samples" => {
+                    let max_samples = parse_number(&flag, &value()?)?;
+                    adaptive(&mut options).max_samples = max_samples;
+                }
+                "--heatmap" => options.heatmap = Some(PathBuf::from(value()?)),
+                "--checkpoint" => options.checkpoint = Some(PathBuf::from(value()?)),
+                "--resume" => options.resume = true,
+                "--tile-size" => options.tile_size = parse_number(&flag, &value()?)?,
+                "--tile-order" => {
+                    let name = value()?;
+                    options.tile_order = TileOrder::from_name(&name).ok_or_else(|| {
+                        format!("unknown tile order `{}`, expected spiral or scanline", name)
+                    })?;
+                }
+                "--crop" => options.crop = Some(parse_region(&flag, &value()?)?),
+                "-j" | "--threads" => options.threads = parse_number(&flag, &value()?)?,
+                "--seed" => options.seed = parse_number(&flag, &value()?)?,
+                "--scene" => {
+                    let name = value()?;
+                    if !BUILTIN_SCENES.contains(&name.as_str()) {
+                        return Err(format!(
+                            "unknown scene `{}`, expected one of: {}",
+                            name,
+                            BUILTIN_SCENES.join(", ")
+                        ));
+                    }
+                    options.scene = SceneSource::Builtin(name);
+                }
+                "--scene-file" => options.scene = SceneSource::File(PathBuf::from(value()?)),
+                "--bvh" => {
+                    let name = value()?;
+                    options.bvh = BvhBuilder::from_name(&name).ok_or_else(|| {
+                        format!("unknown bvh builder `{}`, expected median, sah or flat", name)
+                    })?;
+                }
+                "--integrator" => {
+                    let name = value()?;
+                    options.integrator = Integrator::from_name(&name).ok_or_else(|| {
+                        format!("unknown integrator `{}`, expected path, nee or mis", name)
+                    })?;
+                }
+                "--sampler" => {
+                    let name = value()?;
+                    options.sampler = SamplerKind::from_name(&name).ok_or_else(|| {
+                        format!(
+                            "unknown sampler `{}`, expected random, stratified, halton or sobol",
+                            name
+                        )
+                    })?;
+                }
+                "--filter" => {
+                    let name = value()?;
+                    options.filter.kind = FilterKind::from_name(&name).ok_or_else(|| {
+                        format!(
+                            "unknown filter `{}`, expected box, tent, gaussian, mitchell or \
+                             lanczos",
+                            name
+                        )
+                    })?;
+                }
+                "--filter-radius" => options.filter.radius = parse_number(&flag, &value()?)?,
+                "--exposure" => options.display.exposure = parse_number(&flag, &value()?)?,
+                "--tone-map" => {
+                    let name = value()?;
+                    options.display.tone_map = ToneMap::from_name(&name).ok_or_else(|| {
+                        format!(
+                            "unknown tone map `{}`, expected clamp, reinhard, aces or filmic",
+                            name
+                        )
+                    })?;
+                }
+                "--no-dither" => options.display.dither = false,
+                "--bench-bvh" => options.bench_bvh = true,
+                "--bench-samplers" => options.bench_samplers = true,
+                _ => return Err(format!("unknown option `{}`", arg)),
+            }
+        }
+        if options.width == 0 || options.height == 0 {
+            return Err("image width and height must be at least 1".to_string());
+        }
+        if options.samples == 0 {
+            return Err("samples per pixel must be at least 1".to_string());
+        }
+        if !(options.roulette.min_survival > 0.0 && options.roulette.min_survival <= 1.0) {
+            return Err("roulette survival probability must be in (0, 1]".to_string());
+        }
+        if let Some(clamp) = options.indirect_clamp {
+            if clamp.is_nan() || clamp <= 0.0 {
+                return Err("indirect clamp must be above 0".to_string());
+            }
+        }
+        if let Some(ref mut adaptive) = options.adaptive {
+            // Unset limits follow the sample count.
+            if adaptive.max_samples == 0 {
+                adaptive.max_samples = 4 * options.samples;
+            }
+            if adaptive.min_samples == 0 {
+                adaptive.min_samples = (options.samples / 4).max(2);
+            }
+            if adaptive.threshold.is_nan() || adaptive.threshold <= 0.0 {
+                return Err("--adaptive needs an error threshold above 0".to_string());
+            }
+            if adaptive.min_samples > options.samples || options.samples > adaptive.max_samples {
+                return Err(format!(
+                    "adaptive sampling needs --min-samples {} <= --samples {} <= --max-samples {}",
+                    adaptive.min_samples, options.samples, adaptive.max_samples
+                ));
+            }
+        }
+        if options.heatmap.is_some() && options.adaptive.is_none() {
+            return Err("--heatmap shows adaptive sampling, set --adaptive".to_string());
+        }
+        // An unset radius follows the filter.
+        if options.filter.radius == 0.0 {
+            options.filter.radius = options.filter.kind.default_radius();
+        }
+        if options.filter.radius.is_nan() || options.filter.radius < 0.5 {
+            return Err("filter radius must be at least half a pixel".to_string());
+        }
+        if !options.display.exposure.is_finite() {
+            return Err("exposure must be a finite number of stops".to_string());
+        }
+        if options.tile_size == 0 {
+            return Err("tile size must be at least 1".to_string());
+        }
+        if let Some(crop) = options.crop {
+            if crop.width == 0
+                || crop.height == 0
+                || crop.x.checked_add(crop.width).is_none_or(|end| end > options.width)
+                || crop.y.checked_add(crop.height).is_none_or(|end| end > options.height)
+            {
+                return Err(format!(
+                    "crop window {}x{} at {},{} doesn't fit in the {}x{} image",
+                    crop.width, crop.height, crop.x, crop.y, options.width, options.height
+                ));
+            }
+        }
+        let progressive = options.progressive;
+        if !progressive.snapshot_seconds.is_finite() || progressive.snapshot_seconds < 0.0 {
+            return Err(
+                "snapshot interval must be zero or a positive number of seconds".to_string(),
+            );
+        }
+        if progressive.pass_samples == 0
+            && (progressive.snapshot_passes > 0 || progressive.snapshot_seconds > 0.0)
+        {
+            return Err("snapshots need a progressive render, set --pass-samples".to_string());
+        }
+        if options.checkpoint.is_some()
+            && (progressive.snapshot_passes == 0 && progressive.snapshot_seconds == 0.0)
+        {
+            return Err(
+                "checkpoints are written with snapshots, set --snapshot-passes or \
+                 --snapshot-seconds"
+                    .to_string(),
+            );
+        }
+        if options.resume && options.checkpoint.is_none() {
+            return Err("--resume needs the --checkpoint to resume from".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn parse_number<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("invalid value `{}` for `{}`", value, flag))
+}
+
+// Adaptive settings for the flags that tune them, zero limits are filled in
+// once all options are known.
+fn adaptive(options: &mut Options) -> &mut Adaptive {
+    options.adaptive.get_or_insert(Adaptive {
+        min_samples: 0,
+        max_samples: 0,
+        threshold: 0.0,
+    })
+}
+
+// X, y, width and height separated by commas.
+fn parse_region(flag: &str, value: &str) -> Result<Region, String> {
+    let numbers = value
+        .split(',')
+        .map(|part| parse_number::<u32>(flag, part.trim()))
+        .collect::<Result<Vec<u32>, String>>()?;
+    match numbers[..] {
+        [x, y, width, height] => Ok(Region {
+            x,
+            y,
+            width,
+            height,
+        }),
+        _ => Err(format!(
+            "invalid value `{}` for `{}`, expected <X>,<Y>,<WIDTH>,<HEIGHT>",
+            value, flag
+        )),
+    }
+}
+
+pub fn usage() -> String {
+    let defaults = Options::new();
+    format!(
+        "Usage: rust-ray-tracer [OPTIONS]
+
+Options:
+  -o, --output <PATH>       Output image: .ppm, .png, or linear .pfm/.exr
+                            [default: {}]
+  -W, --width <PIXELS>      Image width [default: {}]
+  -H, --height <PIXELS>     Image height [default: {}]
+  -s, --samples <N>         Samples per pixel [default: {}]
+  -d, --max-depth <N>       Maximum number of ray bounces [default: {}]
+      --roulette-depth <N>  Bounces before paths may be ended by Russian
+                            roulette, at least max depth turns it off
+                            [default: {}]
+      --roulette-min <P>    Lowest survival probability for roulette
+                            [default: {}]
+      --clamp-indirect <MAX>
+                            Scale down light that bounced more than once so
+                            no channel exceeds MAX, trading some energy for
+                            fewer fireflies
+      --debug-invalid       Print the pixel of every NaN or infinite sample
+      --pass-samples <N>    Render progressively in passes of this many
+                            samples per pixel, 0 renders in one pass
+                            [default: {}]
+      --snapshot-passes <N> Write the image so far every N passes
+      --snapshot-seconds <S>
+                            Write the image so far at most every S seconds
+      --adaptive <ERROR>    Sample adaptively, stopping pixels once the
+                            standard error of their displayed value is below
+                            this, 0.03 is a good start. --samples becomes
+                            the average budget
+      --min-samples <N>     Samples every pixel gets before it may stop
+                            [default: a quarter of --samples]
+      --max-samples <N>     Most samples any pixel gets
+                            [default: four times --samples]
+      --heatmap <PATH>      Also write an image of the samples spent per
+                            pixel, brighter is more
+      --checkpoint <PATH>   Save the render state here with every snapshot
+      --resume              Continue the render saved in --checkpoint, with
+                            the same options it was started with
+      --tile-size <PIXELS>  Width and height of the tiles threads pick up
+                            [default: {}]
+      --tile-order <ORDER>  Tile order, spiral from the middle or scanline
+                            from the top [default: {}]
+      --crop <X>,<Y>,<WIDTH>,<HEIGHT>
+                            Only render this part of the image, in pixels
+                            from the top left corner. Pixels come out the
+                            same as in the full image
+  -j, --threads <N>         Render threads, 0 uses every core [default: {}]
+      --seed <N>            Random seed, the same seed and options always
+                            give the same image [default: {}]
+      --scene <NAME>        Built-in scene to render [default: cornell_box]
+                            One of: {}
+      --scene-file <PATH>   Load the scene from a scene description file
+      --bvh <BUILDER>       Bvh construction, median, sah or flat
+                            [default: {}]
+      --integrator <NAME>   Light transport, path only follows scattered rays,
+                            nee also samples lights directly, mis weights
+                            both strategies [default: {}]
+      --sampler <NAME>      Sample pattern, random, stratified, halton or
+                            sobol [default: {}]
+      --filter <NAME>       Reconstruction filter samples are spread over the
+                            pixels around them with, box, tent, gaussian,
+                            mitchell or lanczos [default: {}]
+      --filter-radius <PIXELS>
+                            Filter radius [default: 0.5 for box, 1 for tent,
+                            1.5 for gaussian, 2 for mitchell, 3 for lanczos]
+      --exposure <STOPS>    Brighten the image by 2^STOPS before tone mapping,
+                            negative darkens [default: {}]
+      --tone-map <NAME>     Tone mapping for .png and .ppm output, clamp,
+                            reinhard, aces or filmic [default: {}]
+      --no-dither           Round to 8 bits without dithering
+      --bench-bvh           Time every bvh on the scene's camera rays and one
+                            bounce instead of rendering
+      --bench-samplers      Compare the error of every sampler at doubling
+                            sample counts up to --samples instead of rendering
+  -h, --help                Print this help
+",
+        defaults.output.display(),
+        defaults.width,
+        defaults.height,
+        defaults.samples,
+        defaults.max_depth,
+        defaults.roulette.depth,
+        defaults.roulette.min_survival,
+        defaults.progressive.pass_samples,
+        defaults.tile_size,
+        defaults.tile_order.name(),
+        defaults.threads,
+        defaults.seed,
+        BUILTIN_SCENES.join(", "),
+        defaults.bvh.name(),
+        defaults.integrator.name(),
+        defaults.sampler.name(),
+        defaults.filter.kind.name(),
+        defaults.display.exposure,
+        defaults.display.tone_map.name()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn error(args: &[&str]) -> String {
+        match parse(args) {
+            Ok(_) => panic!("{:?} should be rejected", args),
+            Err(message) => message,
+        }
+    }
+
+    #[test]
+    fn defaults_and_values() {
+        let options = parse(&[]).unwrap();
+        assert_eq!((options.width, options.height, options.samples), (600, 300, 500));
+        assert!(options.crop.is_none());
+
+        let options = parse(&["-W", "32", "--height=16", "-s", "8", "--crop", "4, 2,8,8"]).unwrap();
+        assert_eq!((options.width, options.height, options.samples), (32, 16, 8));
+        assert_eq!(
+            options.crop,
+            Some(Region {
+                x: 4,
+                y: 2,
+                width: 8,
+                height: 8,
+            })
+        );
+        // Unset filter radii follow the filter.
+        let options = parse(&["--filter", "mitchell"]).unwrap();
+        assert_eq!(options.filter.radius, 2.0);
+    }
+
+    #[test]
+    fn rejects_malformed_arguments() {
+        assert_eq!(error(&["--frobnicate"]), "unknown option `--frobnicate`");
+        assert_eq!(error(&["-W"]), "missing value for `-W`");
+        assert_eq!(error(&["-W", "wide"]), "invalid value `wide` for `-W`");
+        assert_eq!(error(&["-s", "0"]), "samples per pixel must be at least 1");
+        assert!(error(&["--crop", "1,2,3"]).contains("expected <X>,<Y>,<WIDTH>,<HEIGHT>"));
+        assert!(error(&["--scene", "nowhere"]).starts_with("unknown scene `nowhere`"));
+        assert!(error(&["--bvh=octree"]).starts_with("unknown bvh builder `octree`"));
+    }
+
+    #[test]
+    fn crop_must_fit_the_image() {
+        let args = ["-W", "32", "-H", "16", "--crop"];
+        let crop = |region: &str| {
+            let mut args = args.to_vec();
+            args.push(region);
+            parse(&args).map(|options| options.crop.unwrap())
+        };
+        assert!(crop("24,8,8,8").is_ok());
+        assert!(crop("25,8,8,8").is_err());
+        assert!(crop("24,9,8,8").is_err());
+        assert!(crop("0,0,0,8").is_err());
+        // The end of the window doesn't fit in a u32.
+        assert_eq!(
+            crop("4294967295,0,1,1").err().unwrap(),
+            "crop window 1x1 at 4294967295,0 doesn't fit in the 32x16 image"
+        );
+        assert!(crop("0,4294967295,1,1").is_err());
+    }
+
+    #[test]
+    fn adaptive_limits_follow_the_samples() {
+        let adaptive = parse(&["-s", "16", "--adaptive", "0.03"])
+            .unwrap()
+            .adaptive
+            .unwrap();
+        assert_eq!((adaptive.min_samples, adaptive.max_samples), (4, 64));
+        assert!(error(&["-s", "16", "--adaptive", "0.03", "--max-samples", "8"])
+            .starts_with("adaptive sampling needs --min-samples 4 <= --samples 16"));
+        assert!(error(&["--min-samples", "4"]).contains("error threshold above 0"));
+        assert!(error(&["--heatmap", "heat.png"]).starts_with("--heatmap shows adaptive"));
+    }
+
+    #[test]
+    fn checkpoints_need_snapshots() {
+        assert!(error(&["--checkpoint", "render.ckpt"]).starts_with("checkpoints are written"));
+        assert!(error(&["--resume"]).starts_with("--resume needs the --checkpoint"));
+        assert!(error(&["--snapshot-passes", "2"]).starts_with("snapshots need a progressive"));
+        let options = parse(&[
+            "--pass-samples",
+            "4",
+            "--snapshot-passes",
+            "2",
+            "--checkpoint",
+            "render.ckpt",
+            "--resume",
+        ]).unwrap();
+        assert!(options.resume);
+    }
+}
@@ -0,0 +1,128 @@
+use std::f32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterKind {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+    Lanczos,
+}
+
+impl FilterKind {
+    pub fn from_name(name: &str) -> Option<FilterKind> {
+        match name {
+            "box" => Some(FilterKind::Box),
+            "tent" => Some(FilterKind::Tent),
+            "gaussian" => Some(FilterKind::Gaussian),
+            "mitchell" => Some(FilterKind::Mitchell),
+            "lanczos" => Some(FilterKind::Lanczos),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FilterKind::Box => "box",
+            FilterKind::Tent => "tent",
+            FilterKind::Gaussian => "gaussian",
+            FilterKind::Mitchell => "mitchell",
+            FilterKind::Lanczos => "lanczos",
+        }
+    }
+
+    pub fn default_radius(&self) -> f32 {
+        match *self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+            FilterKind::Lanczos => 3.0,
+        }
+    }
+}
+
+// Reconstruction filter that weights a sample's contribution to every pixel
+// whose center lies within radius of it, along x and y separately.
+//
+// box: the same weight everywhere, a radius of half a pixel keeps every
+//   sample in its own pixel.
+// tent: falls off linearly to the radius.
+// gaussian: a Gaussian with a standard deviation of a third of the radius,
+//   shifted down to reach zero at it.
+// mitchell: the Mitchell-Netravali cubic with B = C = 1/3, sharper than the
+//   Gaussian with a slight negative lobe.
+// lanczos: a sinc windowed by a sinc stretched to the radius, the sharpest
+//   with the strongest ringing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Filter {
+    pub kind: FilterKind,
+    pub radius: f32,
+}
+
+impl Filter {
+    // Pixels the filter reaches past the one a sample lands in, on each side.
+    pub fn margin(&self) -> u32 {
+        (self.radius - 0.5).ceil().max(0.0) as u32
+    }
+
+    // Weight of a sample at dx, dy pixels from a pixel center.
+    pub fn evaluate(&self, dx: f32, dy: f32) -> f32 {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+
+    fn evaluate_1d(&self, x: f32) -> f32 {
+        let radius = self.radius;
+        match self.kind {
+            // Half open so a sample on the border between two pixels only
+            // counts for one of them.
+            FilterKind::Box => {
+                if x >= -radius && x < radius {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            FilterKind::Tent => (1.0 - x.abs() / radius).max(0.0),
+            FilterKind::Gaussian => {
+                let gaussian = |x: f32| (-4.5 * x * x / (radius * radius)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            FilterKind::Mitchell => mitchell(2.0 * x / radius),
+            FilterKind::Lanczos => {
+                if x.abs() < radius {
+                    sinc(x) * sinc(x / radius)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+fn mitchell(x: f32) -> f32 {
+    let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+    let x = x.abs();
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+            + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+            + (6.0 - 2.0 * b))
+            / 6.0
+    } else if x < 2.0 {
+        ((-b - 6.0 * c) * x * x * x
+            + (6.0 * b + 30.0 * c) * x * x
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c))
+            / 6.0
+    } else {
+        0.0
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (f32::consts::PI * x).sin() / (f32::consts::PI * x)
+    }
+}
@@ -1,24 +1,29 @@
-use rand::{thread_rng, Rng};
+use rand::Rng;
 use std::cmp::Ordering;
+use std::f32;
 
 use ray::*;
-use hitable::{HitRecord, Hitable};
+use vector::Vec3;
+use hitable::{FlatBvh, HitRecord, Hitable, HitableList};
 use utils::{surrounding_box, Aabb};
+use light::Light;
+use sampler::{seeded_rng, BVH_STREAM};
 
 #[derive(Clone, Debug)]
 pub struct BvhNode {
     left: Box<Hitable>,
     right: Box<Hitable>,
     bbox: Aabb,
+    // Axis the children were split along, the left one is on its low side.
+    axis: u8,
 }
 
 impl BvhNode {
-    pub fn new(hitable: &mut [Hitable], time0: f32, time1: f32) -> BvhNode {
+    pub fn new<R: Rng>(hitable: &mut [Hitable], time0: f32, time1: f32, rng: &mut R) -> BvhNode {
         let left: Box<Hitable>;
         let right: Box<Hitable>;
         let n = hitable.len();
-        let mut rng = thread_rng();
-        let axis = (3.0 * rng.gen::<f32>()) as u32;
+        let axis = (3.0 * rng.gen::<f32>()) as u8;
         match axis {
             0 => hitable.sort_by(|a, b| box_x_compare(a, b)),
             1 => hitable.sort_by(|a, b| box_y_compare(a, b)),
@@ -32,8 +37,8 @@ impl BvhNode {
             right = Box::new(hitable[1].clone());
         } else {
             let (vec_start, vec_end) = hitable.split_at_mut(n / 2);
-            left = Box::new(Hitable::BvhNode(BvhNode::new(vec_start, time0, time1)));
-            right = Box::new(Hitable::BvhNode(BvhNode::new(vec_end, time0, time1)));
+            left = Box::new(median_child(vec_start, time0, time1, rng));
+            right = Box::new(median_child(vec_end, time0, time1, rng));
         }
         match (
             left.bounding_box(time0, time1),
@@ -45,6 +50,7 @@ impl BvhNode {
                 left: left,
                 right: right,
                 bbox: surrounding_box(box_left, box_right),
+                axis,
             },
         }
     }
@@ -56,23 +62,35 @@ impl BvhNode {
     }
 
     pub fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        if self.bbox.hit(r, t_min, t_max) {
-            let hit_left = self.left.hit(r, t_min, t_max);
-            let hit_right = self.right.hit(r, t_min, t_max);
-            match (hit_left, hit_right) {
-                (Some(left_rec), Some(right_rec)) => {
-                    if left_rec.t < right_rec.t {
-                        return Some(left_rec);
-                    } else {
-                        return Some(right_rec);
-                    }
-                }
-                (Some(left_rec), _) => return Some(left_rec),
-                (_, Some(right_rec)) => return Some(right_rec),
-                (None, None) => return None,
-            }
+        if !self.bbox.hit(r, t_min, t_max) {
+            return None;
+        }
+        // The nearer child goes first so its closest hit can cut the far
+        // child off at the box test.
+        let (near, far) = if r.direction()[self.axis] < 0.0 {
+            (&self.right, &self.left)
+        } else {
+            (&self.left, &self.right)
+        };
+        match near.hit(r, t_min, t_max) {
+            Some(near_rec) => far.hit(r, t_min, near_rec.t).or(Some(near_rec)),
+            None => far.hit(r, t_min, t_max),
         }
-        None
+    }
+
+    pub fn collect_lights<'a>(&'a self, offset: Vec3, lights: &mut Vec<Light<'a>>) {
+        self.left.collect_lights(offset, lights);
+        self.right.collect_lights(offset, lights);
+    }
+}
+
+// A single primitive goes straight into its parent, a node of its own would
+// hold it twice and lights inside it would be sampled twice.
+fn median_child<R: Rng>(hitable: &mut [Hitable], time0: f32, time1: f32, rng: &mut R) -> Hitable {
+    if hitable.len() == 1 {
+        hitable[0].clone()
+    } else {
+        Hitable::BvhNode(BvhNode::new(hitable, time0, time1, rng))
     }
 }
 
@@ -108,3 +126,256 @@ fn box_z_compare(a: &Hitable, b: &Hitable) -> Ordering {
     }
     Ordering::Greater
 }
+
+const SAH_BINS: usize = 16;
+pub(crate) const SAH_TRAVERSAL_COST: f32 = 1.0;
+pub(crate) const SAH_INTERSECTION_COST: f32 = 1.0;
+const SAH_MAX_LEAF_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BvhBuilder {
+    Median,
+    Sah,
+    Flat,
+}
+
+impl BvhBuilder {
+    pub fn from_name(name: &str) -> Option<BvhBuilder> {
+        match name {
+            "median" => Some(BvhBuilder::Median),
+            "sah" => Some(BvhBuilder::Sah),
+            "flat" => Some(BvhBuilder::Flat),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            BvhBuilder::Median => "median",
+            BvhBuilder::Sah => "sah",
+            BvhBuilder::Flat => "flat",
+        }
+    }
+
+    pub fn build(&self, mut hitables: Vec<Hitable>, time0: f32, time1: f32) -> Hitable {
+        match *self {
+            // The split axes only shape the tree, never the image, so they
+            // don't follow the render seed.
+            BvhBuilder::Median => {
+                median_child(&mut hitables, time0, time1, &mut seeded_rng(&[BVH_STREAM]))
+            }
+            BvhBuilder::Sah => build_sah(hitables, time0, time1),
+            BvhBuilder::Flat => Hitable::FlatBvh(FlatBvh::new(hitables, time0, time1)),
+        }
+    }
+}
+
+pub(crate) struct BuildItem {
+    index: usize,
+    bbox: Aabb,
+    centroid: Vec3,
+}
+
+impl BuildItem {
+    pub(crate) fn index(&self) -> usize {
+        self.index
+    }
+}
+
+pub(crate) enum SahSplit {
+    Leaf(Vec<BuildItem>),
+    Node(u8, Vec<BuildItem>, Vec<BuildItem>),
+}
+
+// Binned surface area heuristic build. Splits are only chosen by cost, so the
+// same input always gives the same tree. Small sets of primitives that are
+// cheaper to test together than to split end up in a HitableList leaf.
+pub fn build_sah(hitables: Vec<Hitable>, time0: f32, time1: f32) -> Hitable {
+    let items = sah_items(&hitables, time0, time1);
+    // The split search only shuffles indices around, the primitives are
+    // moved into the tree once their leaf is known.
+    let mut hitables: Vec<Option<Hitable>> = hitables.into_iter().map(Some).collect();
+    build_sah_node(items, &mut hitables)
+}
+
+fn build_sah_node(items: Vec<BuildItem>, hitables: &mut [Option<Hitable>]) -> Hitable {
+    match sah_split(items) {
+        (_, SahSplit::Leaf(ref items)) if items.len() == 1 => {
+            hitables[items[0].index].take().unwrap()
+        }
+        (_, SahSplit::Leaf(items)) => Hitable::HitableList(HitableList::new(
+            items
+                .iter()
+                .map(|item| hitables[item.index].take().unwrap())
+                .collect(),
+        )),
+        (bbox, SahSplit::Node(axis, left, right)) => Hitable::BvhNode(BvhNode {
+            left: Box::new(build_sah_node(left, hitables)),
+            right: Box::new(build_sah_node(right, hitables)),
+            bbox,
+            axis,
+        }),
+    }
+}
+
+pub(crate) fn sah_items(hitables: &[Hitable], time0: f32, time1: f32) -> Vec<BuildItem> {
+    hitables
+        .iter()
+        .enumerate()
+        .map(|(index, hitable)| {
+            let bbox = hitable
+                .bounding_box(time0, time1)
+                .expect("No bounding box in bvh construction");
+            BuildItem {
+                index,
+                centroid: bbox.centroid(),
+                bbox,
+            }
+        })
+        .collect()
+}
+
+// Bounds of the items and either the leaf they should share or the best split
+// with its axis.
+pub(crate) fn sah_split(mut items: Vec<BuildItem>) -> (Aabb, SahSplit) {
+    let n = items.len();
+    let mut bbox = items[0].bbox.clone();
+    let mut centroid_min = items[0].centroid;
+    let mut centroid_max = items[0].centroid;
+    for item in &items[1..] {
+        bbox = surrounding_box(bbox, item.bbox.clone());
+        for a in 0..3 {
+            centroid_min[a] = centroid_min[a].min(item.centroid[a]);
+            centroid_max[a] = centroid_max[a].max(item.centroid[a]);
+        }
+    }
+    if n == 1 {
+        return (bbox, SahSplit::Leaf(items));
+    }
+
+    let bin_of = |centroid: &Vec3, axis: u8| -> usize {
+        let extent = centroid_max[axis] - centroid_min[axis];
+        let bin = ((centroid[axis] - centroid_min[axis]) / extent * SAH_BINS as f32) as usize;
+        bin.min(SAH_BINS - 1)
+    };
+
+    // Best split as (cost, axis, last bin on the left side).
+    let mut best: Option<(f32, u8, usize)> = None;
+    let parent_area = bbox.surface_area().max(f32::MIN_POSITIVE);
+    for axis in 0..3u8 {
+        if centroid_max[axis] - centroid_min[axis] <= 0.0 {
+            continue;
+        }
+        let mut counts = [0usize; SAH_BINS];
+        let mut bounds: Vec<Option<Aabb>> = vec![None; SAH_BINS];
+        for item in &items {
+            let bin = bin_of(&item.centroid, axis);
+            counts[bin] += 1;
+            bounds[bin] = Some(match bounds[bin].take() {
+                Some(b) => surrounding_box(b, item.bbox.clone()),
+                None => item.bbox.clone(),
+            });
+        }
+        // Sweep from the right to know the cost of every right hand side.
+        let mut right_cost = [0.0f32; SAH_BINS];
+        let mut right_box: Option<Aabb> = None;
+        let mut right_count = 0;
+        for bin in (1..SAH_BINS).rev() {
+            right_count += counts[bin];
+            right_box = merge(right_box, bounds[bin].clone());
+            right_cost[bin - 1] = area(&right_box) * right_count as f32;
+        }
+        let mut left_box: Option<Aabb> = None;
+        let mut left_count = 0;
+        for split in 0..SAH_BINS - 1 {
+            left_count += counts[split];
+            left_box = merge(left_box, bounds[split].clone());
+            if left_count == 0 || left_count == n {
+                continue;
+            }
+            let cost = SAH_TRAVERSAL_COST
+                + SAH_INTERSECTION_COST * (area(&left_box) * left_count as f32 + right_cost[split])
+                    / parent_area;
+            if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                best = Some((cost, axis, split));
+            }
+        }
+    }
+
+    let leaf_cost = SAH_INTERSECTION_COST * n as f32;
+    let split = match best {
+        Some((cost, _, _)) if cost >= leaf_cost && n <= SAH_MAX_LEAF_SIZE => SahSplit::Leaf(items),
+        Some((_, axis, split)) => {
+            let (left, right) = items
+                .into_iter()
+                .partition(|item| bin_of(&item.centroid, axis) <= split);
+            SahSplit::Node(axis, left, right)
+        }
+        // Every centroid is in the same spot, so no split helps.
+        None if n <= SAH_MAX_LEAF_SIZE => SahSplit::Leaf(items),
+        None => {
+            let right = items.split_off(n / 2);
+            SahSplit::Node(0, items, right)
+        }
+    };
+    (bbox, split)
+}
+
+fn merge(a: Option<Aabb>, b: Option<Aabb>) -> Option<Aabb> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(surrounding_box(a, b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+fn area(bbox: &Option<Aabb>) -> f32 {
+    bbox.as_ref().map_or(0.0, |bbox| bbox.surface_area())
+}
+
+#[derive(Debug, Default)]
+pub struct BvhStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    pub primitives: usize,
+    pub max_depth: usize,
+    pub sah_cost: f32,
+}
+
+impl BvhStats {
+    // Walks a tree made by either builder. The SAH cost uses the same
+    // constants as the builder, relative to the surface area of the root.
+    pub fn of(root: &Hitable, time0: f32, time1: f32) -> BvhStats {
+        let mut stats = BvhStats::default();
+        if let Some(bbox) = root.bounding_box(time0, time1) {
+            let root_area = bbox.surface_area().max(f32::MIN_POSITIVE);
+            stats.visit(root, 0, root_area, time0, time1);
+        }
+        stats
+    }
+
+    fn visit(&mut self, hitable: &Hitable, depth: usize, root_area: f32, time0: f32, time1: f32) {
+        self.max_depth = self.max_depth.max(depth);
+        let area = hitable
+            .bounding_box(time0, time1)
+            .map_or(0.0, |bbox| bbox.surface_area());
+        match *hitable {
+            Hitable::BvhNode(ref node) => {
+                self.nodes += 1;
+                self.sah_cost += SAH_TRAVERSAL_COST * area / root_area;
+                self.visit(&node.left, depth + 1, root_area, time0, time1);
+                self.visit(&node.right, depth + 1, root_area, time0, time1);
+            }
+            Hitable::FlatBvh(ref bvh) => bvh.add_stats(self, depth, root_area),
+            _ => {
+                let primitives = match *hitable {
+                    Hitable::HitableList(ref list) => list.list.len(),
+                    _ => 1,
+                };
+                self.leaves += 1;
+                self.primitives += primitives;
+                self.sah_cost += SAH_INTERSECTION_COST * primitives as f32 * area / root_area;
+            }
+        }
+    }
+}
@@ -1,5 +1,4 @@
 use std::f32;
-use rand::{thread_rng, Rng};
 
 use hitable::{HitRecord, Hitable};
 use utils::Aabb;
@@ -7,6 +6,7 @@ use vector::*;
 use ray::*;
 use material::*;
 use texture::*;
+use sampler::hash_to_unit;
 
 #[derive(Clone, Debug)]
 pub struct ConstantMedium {
@@ -25,8 +25,6 @@ impl ConstantMedium {
     }
 
     pub fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let mut rng = thread_rng();
-        let db = rng.gen::<f32>() < 0.00001;
         match self.boundary.hit(r, f32::MIN, f32::MAX) {
             Some(mut rec1) => match self.boundary.hit(r, rec1.t + 0.0001, f32::MAX) {
                 Some(mut rec2) => {
@@ -40,16 +38,20 @@ impl ConstantMedium {
                         return None;
                     }
                     let distance_inside_boundary = (rec2.t - rec1.t) * r.direction().length();
-                    let hit_distance = -(1.0 / self.density) * (rng.gen::<f32>()).ln();
+                    let hit_distance = -(1.0 / self.density) * scatter_random(r).ln();
                     if hit_distance < distance_inside_boundary {
                         let t = rec1.t + hit_distance / r.direction().length();
                         return Some(HitRecord {
                             t: t,
                             p: r.point_at_parameter(t),
                             normal: Vec3(1.0, 0.0, 0.0),
+                            dpdu: Vec3(0.0, 0.0, 0.0),
+                            dpdv: Vec3(0.0, 0.0, 0.0),
                             material: &self.phase_function,
                             u: 0.0,
                             v: 0.0,
+                            vertex_color: None,
+                            shape: 0,
                         });
                     }
                 }
@@ -64,3 +66,20 @@ impl ConstantMedium {
         self.boundary.bounding_box(t0, t1)
     }
 }
+
+// Hits have no sampler to draw from, so the scattering distance is picked by
+// hashing the ray. A path never traces the same ray twice, and the same ray
+// always finds the same distance no matter which thread traces it.
+fn scatter_random(r: &Ray) -> f32 {
+    let origin = r.origin();
+    let direction = r.direction();
+    hash_to_unit(&[
+        u64::from(origin.x().to_bits()),
+        u64::from(origin.y().to_bits()),
+        u64::from(origin.z().to_bits()),
+        u64::from(direction.x().to_bits()),
+        u64::from(direction.y().to_bits()),
+        u64::from(direction.z().to_bits()),
+        u64::from(r.time().to_bits()),
+    ])
+}
@@ -0,0 +1,171 @@
+use ray::*;
+use vector::Vec3;
+use hitable::{
+    sah_items, sah_split, BuildItem, BvhStats, HitRecord, Hitable, SahSplit, SAH_INTERSECTION_COST,
+    SAH_TRAVERSAL_COST,
+};
+use utils::Aabb;
+use light::Light;
+
+// Traversal keeps its stack on the call frame, so the builder turns whatever
+// is left at this depth into a single leaf.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Debug)]
+struct LinearNode {
+    bbox: Aabb,
+    // First primitive of a leaf, or the second child of an interior node. The
+    // first child is always stored right after its parent.
+    offset: u32,
+    // Number of primitives, zero for interior nodes.
+    count: u32,
+    axis: u8,
+}
+
+// A SAH bvh laid out depth first in one array. Primitives are moved into
+// leaf order once and leaves refer to them by index, so nothing is cloned
+// and a traversal only touches two contiguous vectors.
+#[derive(Clone, Debug)]
+pub struct FlatBvh {
+    nodes: Vec<LinearNode>,
+    primitives: Vec<Hitable>,
+}
+
+impl FlatBvh {
+    pub fn new(hitables: Vec<Hitable>, time0: f32, time1: f32) -> FlatBvh {
+        let items = sah_items(&hitables, time0, time1);
+        let mut bvh = FlatBvh {
+            nodes: Vec::with_capacity(2 * hitables.len()),
+            primitives: Vec::with_capacity(hitables.len()),
+        };
+        if !items.is_empty() {
+            let mut slots: Vec<Option<Hitable>> = hitables.into_iter().map(Some).collect();
+            bvh.flatten(items, &mut slots, 0);
+        }
+        bvh
+    }
+
+    fn flatten(
+        &mut self,
+        items: Vec<BuildItem>,
+        slots: &mut [Option<Hitable>],
+        depth: usize,
+    ) -> usize {
+        let index = self.nodes.len();
+        let (bbox, split) = sah_split(items);
+        let split = match split {
+            SahSplit::Node(_, mut left, right) if depth + 1 >= MAX_DEPTH => {
+                left.extend(right);
+                SahSplit::Leaf(left)
+            }
+            split => split,
+        };
+        match split {
+            SahSplit::Leaf(items) => {
+                self.nodes.push(LinearNode {
+                    bbox,
+                    offset: self.primitives.len() as u32,
+                    count: items.len() as u32,
+                    axis: 0,
+                });
+                for item in items {
+                    self.primitives.push(slots[item.index()].take().unwrap());
+                }
+            }
+            SahSplit::Node(axis, left, right) => {
+                self.nodes.push(LinearNode {
+                    bbox,
+                    offset: 0,
+                    count: 0,
+                    axis,
+                });
+                self.flatten(left, slots, depth + 1);
+                let second = self.flatten(right, slots, depth + 1);
+                self.nodes[index].offset = second as u32;
+            }
+        }
+        index
+    }
+
+    pub fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let origin = r.origin();
+        let direction = r.direction();
+        let inv_direction = Vec3(
+            1.0 / direction.x(),
+            1.0 / direction.y(),
+            1.0 / direction.z(),
+        );
+        let mut closest = t_max;
+        let mut result = None;
+        let mut stack = [0usize; MAX_DEPTH];
+        let mut stack_len = 0;
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            // Boxes further away than the closest hit so far are skipped, so
+            // visiting the nearer child first prunes most of the far one.
+            if node.bbox.hit_inverse(&origin, &inv_direction, t_min, closest) {
+                if node.count > 0 {
+                    let start = node.offset as usize;
+                    for primitive in &self.primitives[start..start + node.count as usize] {
+                        if let Some(rec) = primitive.hit(r, t_min, closest) {
+                            closest = rec.t;
+                            result = Some(rec);
+                        }
+                    }
+                } else {
+                    let (near, far) = if inv_direction[node.axis] < 0.0 {
+                        (node.offset as usize, current + 1)
+                    } else {
+                        (current + 1, node.offset as usize)
+                    };
+                    stack[stack_len] = far;
+                    stack_len += 1;
+                    current = near;
+                    continue;
+                }
+            }
+            if stack_len == 0 {
+                break;
+            }
+            stack_len -= 1;
+            current = stack[stack_len];
+        }
+        result
+    }
+
+    pub fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<Aabb> {
+        self.nodes.first().map(|node| node.bbox.clone())
+    }
+
+    pub fn collect_lights<'a>(&'a self, offset: Vec3, lights: &mut Vec<Light<'a>>) {
+        for primitive in &self.primitives {
+            primitive.collect_lights(offset, lights);
+        }
+    }
+
+    pub fn add_stats(&self, stats: &mut BvhStats, depth: usize, root_area: f32) {
+        if !self.nodes.is_empty() {
+            self.visit(0, stats, depth, root_area);
+        }
+    }
+
+    fn visit(&self, index: usize, stats: &mut BvhStats, depth: usize, root_area: f32) {
+        let node = &self.nodes[index];
+        let area = node.bbox.surface_area();
+        stats.max_depth = stats.max_depth.max(depth);
+        if node.count > 0 {
+            stats.leaves += 1;
+            stats.primitives += node.count as usize;
+            stats.sah_cost += SAH_INTERSECTION_COST * node.count as f32 * area / root_area;
+        } else {
+            stats.nodes += 1;
+            stats.sah_cost += SAH_TRAVERSAL_COST * area / root_area;
+            self.visit(index + 1, stats, depth + 1, root_area);
+            self.visit(node.offset as usize, stats, depth + 1, root_area);
+        }
+    }
+}
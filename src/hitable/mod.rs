@@ -2,24 +2,37 @@ use std::f32;
 
 pub mod rotate;
 pub mod bvhnode;
+pub mod flatbvh;
 pub mod constantmedium;
+pub mod triangle;
 
 use vector::Vec3;
 use ray::*;
 use material::*;
 use utils::*;
 use sphere::*;
+use light::*;
 pub use self::rotate::*;
 pub use self::bvhnode::*;
+pub use self::flatbvh::*;
 pub use self::constantmedium::*;
+pub use self::triangle::*;
 
+// dpdu and dpdv are how p moves with u and v, zero where a shape has no
+// such parametrization. shape identifies the rect or sphere that was hit so
+// the light made from it can be found, it's zero for every other shape.
+#[derive(Clone)]
 pub struct HitRecord<'a> {
     pub t: f32,
     pub p: Vec3,
     pub normal: Vec3,
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
     pub material: &'a Material,
     pub u: f32,
     pub v: f32,
+    pub vertex_color: Option<Vec3>,
+    pub shape: usize,
 }
 
 impl<'a> HitRecord<'a> {
@@ -28,15 +41,33 @@ impl<'a> HitRecord<'a> {
             t: t,
             p: p,
             normal: normal,
+            dpdu: Vec3(0.0, 0.0, 0.0),
+            dpdv: Vec3(0.0, 0.0, 0.0),
             material: material,
             u: u,
             v: v,
+            vertex_color: None,
+            shape: 0,
         }
     }
 
-    pub fn scatter(&self, r_in: &Ray) -> Scattered<(Ray, Vec3)> {
-        self.material.scatter(r_in, self)
+    pub fn with_tangents(mut self, dpdu: Vec3, dpdv: Vec3) -> HitRecord<'a> {
+        self.dpdu = dpdu;
+        self.dpdv = dpdv;
+        self
     }
+
+    pub fn with_shape(mut self, shape: usize) -> HitRecord<'a> {
+        self.shape = shape;
+        self
+    }
+}
+
+// Primitives are told apart by their address, which stays put while a
+// LightList borrows the world. Materials can't be used for this since
+// several primitives may share one.
+pub fn shape_id<T>(shape: &T) -> usize {
+    shape as *const T as usize
 }
 
 #[derive(Clone, Debug)]
@@ -45,6 +76,7 @@ pub enum Hitable {
     Sphere(Sphere),
     MovingSphere(MovingSphere),
     BvhNode(BvhNode),
+    FlatBvh(FlatBvh),
     XYRect(XYRect),
     XZRect(XZRect),
     YZRect(YZRect),
@@ -53,6 +85,8 @@ pub enum Hitable {
     Translate(Translate),
     RotateY(RotateY),
     ConstantMedium(ConstantMedium),
+    Triangle(Triangle),
+    TriangleMesh(TriangleMesh),
 }
 
 impl Hitable {
@@ -62,6 +96,7 @@ impl Hitable {
             Hitable::Sphere(ref sphere) => sphere.hit(r, t_min, t_max),
             Hitable::MovingSphere(ref moving_sphere) => moving_sphere.hit(r, t_min, t_max),
             Hitable::BvhNode(ref bvh_node) => bvh_node.hit(r, t_min, t_max),
+            Hitable::FlatBvh(ref flat_bvh) => flat_bvh.hit(r, t_min, t_max),
             Hitable::XYRect(ref xyrect) => xyrect.hit(r, t_min, t_max),
             Hitable::XZRect(ref xzrect) => xzrect.hit(r, t_min, t_max),
             Hitable::YZRect(ref yzrect) => yzrect.hit(r, t_min, t_max),
@@ -70,6 +105,8 @@ impl Hitable {
             Hitable::Translate(ref translate) => translate.hit(r, t_min, t_max),
             Hitable::RotateY(ref rotate_y) => rotate_y.hit(r, t_min, t_max),
             Hitable::ConstantMedium(ref conmed) => conmed.hit(r, t_min, t_max),
+            Hitable::Triangle(ref triangle) => triangle.hit(r, t_min, t_max),
+            Hitable::TriangleMesh(ref mesh) => mesh.hit(r, t_min, t_max),
         }
     }
     pub fn bounding_box(&self, t0: f32, t1: f32) -> Option<Aabb> {
@@ -78,6 +115,7 @@ impl Hitable {
             Hitable::Sphere(ref sphere) => sphere.bounding_box(t0, t1),
             Hitable::MovingSphere(ref moving_sphere) => moving_sphere.bounding_box(t0, t1),
             Hitable::BvhNode(ref bvh_node) => bvh_node.bounding_box(t0, t1),
+            Hitable::FlatBvh(ref flat_bvh) => flat_bvh.bounding_box(t0, t1),
             Hitable::XYRect(ref xyrect) => xyrect.bounding_box(t0, t1),
             Hitable::XZRect(ref xzrect) => xzrect.bounding_box(t0, t1),
             Hitable::YZRect(ref yzrect) => yzrect.bounding_box(t0, t1),
@@ -86,6 +124,25 @@ impl Hitable {
             Hitable::Translate(ref translate) => translate.bounding_box(t0, t1),
             Hitable::RotateY(ref rotate_y) => rotate_y.bounding_box(t0, t1),
             Hitable::ConstantMedium(ref conmed) => conmed.bounding_box(t0, t1),
+            Hitable::Triangle(ref triangle) => triangle.bounding_box(t0, t1),
+            Hitable::TriangleMesh(ref mesh) => mesh.bounding_box(t0, t1),
+        }
+    }
+
+    // Adds every emitter that can be sampled directly, moved by offset.
+    pub fn collect_lights<'a>(&'a self, offset: Vec3, lights: &mut Vec<Light<'a>>) {
+        match *self {
+            Hitable::HitableList(ref hitable_list) => hitable_list.collect_lights(offset, lights),
+            Hitable::Sphere(ref sphere) => sphere.collect_lights(offset, lights),
+            Hitable::BvhNode(ref bvh_node) => bvh_node.collect_lights(offset, lights),
+            Hitable::FlatBvh(ref flat_bvh) => flat_bvh.collect_lights(offset, lights),
+            Hitable::XYRect(ref xyrect) => xyrect.collect_lights(offset, lights),
+            Hitable::XZRect(ref xzrect) => xzrect.collect_lights(offset, lights),
+            Hitable::YZRect(ref yzrect) => yzrect.collect_lights(offset, lights),
+            Hitable::FlipNormals(ref flip_normals) => flip_normals.collect_lights(offset, lights),
+            Hitable::Box_(ref box_) => box_.collect_lights(offset, lights),
+            Hitable::Translate(ref translate) => translate.collect_lights(offset, lights),
+            _ => {}
         }
     }
 }
@@ -121,6 +178,10 @@ impl Translate {
             None => None,
         }
     }
+
+    fn collect_lights<'a>(&'a self, offset: Vec3, lights: &mut Vec<Light<'a>>) {
+        self.ptr.collect_lights(offset + self.offset, lights);
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -195,6 +256,10 @@ impl Box_ {
     fn bounding_box(&self, t0: f32, t1: f32) -> Option<Aabb> {
         Some(Aabb::new(self.pmin, self.pmax))
     }
+
+    fn collect_lights<'a>(&'a self, offset: Vec3, lights: &mut Vec<Light<'a>>) {
+        self.list_ptr.collect_lights(offset, lights);
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -220,6 +285,11 @@ impl FlipNormals {
     fn bounding_box(&self, t0: f32, t1: f32) -> Option<Aabb> {
         self.ptr.bounding_box(t0, t1)
     }
+
+    // Diffuse lights shine from both sides, so flipping changes nothing.
+    fn collect_lights<'a>(&'a self, offset: Vec3, lights: &mut Vec<Light<'a>>) {
+        self.ptr.collect_lights(offset, lights);
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -251,6 +321,18 @@ impl YZRect {
         ))
     }
 
+    fn collect_lights<'a>(&'a self, offset: Vec3, lights: &mut Vec<Light<'a>>) {
+        if self.mp.is_light() {
+            lights.push(Light::Rect(RectLight::new(
+                Vec3(self.k, self.y0, self.z0) + offset,
+                Vec3(0.0, self.y1 - self.y0, 0.0),
+                Vec3(0.0, 0.0, self.z1 - self.z0),
+                &self.mp,
+                shape_id(self),
+            )));
+        }
+    }
+
     fn hit(&self, r: &Ray, t0: f32, t1: f32) -> Option<HitRecord> {
         let t = (self.k - r.origin().x()) / r.direction().x();
         if t < t0 || t > t1 {
@@ -268,7 +350,10 @@ impl YZRect {
             &self.mp,
             (y - self.y0) / (self.y1 - self.y0),
             (z - self.z0) / (self.z1 - self.z0),
-        ))
+        ).with_tangents(
+            Vec3(0.0, self.y1 - self.y0, 0.0),
+            Vec3(0.0, 0.0, self.z1 - self.z0),
+        ).with_shape(shape_id(self)))
     }
 }
 
@@ -301,6 +386,18 @@ impl XZRect {
         ))
     }
 
+    fn collect_lights<'a>(&'a self, offset: Vec3, lights: &mut Vec<Light<'a>>) {
+        if self.mp.is_light() {
+            lights.push(Light::Rect(RectLight::new(
+                Vec3(self.x0, self.k, self.z0) + offset,
+                Vec3(self.x1 - self.x0, 0.0, 0.0),
+                Vec3(0.0, 0.0, self.z1 - self.z0),
+                &self.mp,
+                shape_id(self),
+            )));
+        }
+    }
+
     fn hit(&self, r: &Ray, t0: f32, t1: f32) -> Option<HitRecord> {
         let t = (self.k - r.origin().y()) / r.direction().y();
         if t < t0 || t > t1 {
@@ -318,7 +415,10 @@ impl XZRect {
             &self.mp,
             (x - self.x0) / (self.x1 - self.x0),
             (z - self.z0) / (self.z1 - self.z0),
-        ))
+        ).with_tangents(
+            Vec3(self.x1 - self.x0, 0.0, 0.0),
+            Vec3(0.0, 0.0, self.z1 - self.z0),
+        ).with_shape(shape_id(self)))
     }
 }
 
@@ -361,7 +461,10 @@ impl XYRect {
             &self.mp,
             (x - self.x0) / (self.x1 - self.x0),
             (y - self.y0) / (self.y1 - self.y0),
-        ))
+        ).with_tangents(
+            Vec3(self.x1 - self.x0, 0.0, 0.0),
+            Vec3(0.0, self.y1 - self.y0, 0.0),
+        ).with_shape(shape_id(self)))
     }
 
     fn bounding_box(&self, t0: f32, t1: f32) -> Option<Aabb> {
@@ -370,6 +473,18 @@ impl XYRect {
             Vec3(self.x1, self.y1, self.k + 0.0001),
         ))
     }
+
+    fn collect_lights<'a>(&'a self, offset: Vec3, lights: &mut Vec<Light<'a>>) {
+        if self.mp.is_light() {
+            lights.push(Light::Rect(RectLight::new(
+                Vec3(self.x0, self.y0, self.k) + offset,
+                Vec3(self.x1 - self.x0, 0.0, 0.0),
+                Vec3(0.0, self.y1 - self.y0, 0.0),
+                &self.mp,
+                shape_id(self),
+            )));
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -415,4 +530,10 @@ impl HitableList {
         }
         Some(temp_box)
     }
+
+    fn collect_lights<'a>(&'a self, offset: Vec3, lights: &mut Vec<Light<'a>>) {
+        for hitable in &self.list {
+            hitable.collect_lights(offset, lights);
+        }
+    }
 }
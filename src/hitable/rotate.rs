@@ -69,14 +69,10 @@ impl RotateY {
         let rotated_r = Ray::new(origin, direction, r.time());
         match self.ptr.hit(&rotated_r, t0, t1) {
             Some(mut rec) => {
-                let mut p = rec.p;
-                let mut normal = rec.normal;
-                p[0] = self.cos_theta * rec.p[0] + self.sin_theta * rec.p[2];
-                p[2] = -self.sin_theta * rec.p[0] + self.cos_theta * rec.p[2];
-                normal[0] = self.cos_theta * rec.normal[0] + self.sin_theta * rec.normal[2];
-                normal[2] = -self.sin_theta * rec.normal[0] + self.cos_theta * rec.normal[2];
-                rec.p = p;
-                rec.normal = normal;
+                rec.p = self.rotate_back(&rec.p);
+                rec.normal = self.rotate_back(&rec.normal);
+                rec.dpdu = self.rotate_back(&rec.dpdu);
+                rec.dpdv = self.rotate_back(&rec.dpdv);
                 return Some(rec);
             }
             None => {
@@ -85,6 +81,15 @@ impl RotateY {
         }
     }
 
+    // From the rotated frame the object was hit in back to the world.
+    fn rotate_back(&self, v: &Vec3) -> Vec3 {
+        Vec3(
+            self.cos_theta * v[0] + self.sin_theta * v[2],
+            v[1],
+            -self.sin_theta * v[0] + self.cos_theta * v[2],
+        )
+    }
+
     pub fn bounding_box(&self, t0: f32, t1: f32) -> Option<Aabb> {
         if self.hasbox {
             Some(self.bbox.clone())
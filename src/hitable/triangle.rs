@@ -0,0 +1,240 @@
+use std::f32;
+use std::sync::Arc;
+
+use hitable::{FlatBvh, HitRecord, Hitable};
+use utils::Aabb;
+use vector::*;
+use ray::*;
+use material::*;
+
+// Vertex data shared by every triangle of a mesh. Normals, uvs and colors are
+// either empty or hold one entry per position, face materials are either
+// empty or index into materials for every face.
+#[derive(Debug)]
+pub struct Mesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<(f32, f32)>,
+    pub colors: Vec<Vec3>,
+    pub indices: Vec<[u32; 3]>,
+    pub materials: Vec<Material>,
+    pub face_materials: Vec<u32>,
+}
+
+impl Mesh {
+    pub fn new(positions: Vec<Vec3>, indices: Vec<[u32; 3]>, material: Material) -> Mesh {
+        Mesh {
+            positions,
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            colors: Vec::new(),
+            indices,
+            materials: vec![material],
+            face_materials: Vec::new(),
+        }
+    }
+
+    pub fn material(&self, face: usize) -> &Material {
+        if self.face_materials.is_empty() {
+            &self.materials[0]
+        } else {
+            &self.materials[self.face_materials[face] as usize]
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.indices.is_empty() {
+            return Err("mesh has no faces".to_string());
+        }
+        if !self.normals.is_empty() && self.normals.len() != self.positions.len() {
+            return Err("mesh needs exactly one normal per vertex".to_string());
+        }
+        if !self.uvs.is_empty() && self.uvs.len() != self.positions.len() {
+            return Err("mesh needs exactly one uv per vertex".to_string());
+        }
+        if !self.colors.is_empty() && self.colors.len() != self.positions.len() {
+            return Err("mesh needs exactly one color per vertex".to_string());
+        }
+        if self.materials.is_empty() {
+            return Err("mesh has no material".to_string());
+        }
+        if !self.face_materials.is_empty() {
+            if self.face_materials.len() != self.indices.len() {
+                return Err("mesh needs exactly one material per face".to_string());
+            }
+            if let Some(index) = self
+                .face_materials
+                .iter()
+                .find(|&&index| index as usize >= self.materials.len())
+            {
+                return Err(format!("face refers to missing material {}", index));
+            }
+        }
+        for face in &self.indices {
+            for &index in face {
+                if index as usize >= self.positions.len() {
+                    return Err(format!("face refers to missing vertex {}", index));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Triangle {
+    mesh: Arc<Mesh>,
+    face: usize,
+}
+
+impl Triangle {
+    pub fn new(v0: Vec3, v1: Vec3, v2: Vec3, material: Material) -> Triangle {
+        Triangle {
+            mesh: Arc::new(Mesh::new(vec![v0, v1, v2], vec![[0, 1, 2]], material)),
+            face: 0,
+        }
+    }
+
+    pub fn from_mesh(mesh: Arc<Mesh>, face: usize) -> Triangle {
+        Triangle { mesh, face }
+    }
+
+    fn vertices(&self) -> (usize, usize, usize) {
+        let face = self.mesh.indices[self.face];
+        (face[0] as usize, face[1] as usize, face[2] as usize)
+    }
+
+    pub fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let (i0, i1, i2) = self.vertices();
+        let p0 = self.mesh.positions[i0];
+        let e1 = self.mesh.positions[i1] - p0;
+        let e2 = self.mesh.positions[i2] - p0;
+        let pvec = cross(&r.direction(), &e2);
+        let det = dot(&e1, &pvec);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let tvec = r.origin() - p0;
+        let b1 = dot(&tvec, &pvec) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let qvec = cross(&tvec, &e1);
+        let b2 = dot(&r.direction(), &qvec) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let t = dot(&e2, &qvec) * inv_det;
+        if t < t_min || t > t_max {
+            return None;
+        }
+        let b0 = 1.0 - b1 - b2;
+        let normal = if self.mesh.normals.is_empty() {
+            unit_vector(cross(&e1, &e2))
+        } else {
+            let n = &self.mesh.normals;
+            unit_vector(n[i0] * b0 + n[i1] * b1 + n[i2] * b2)
+        };
+        let (u, v, dpdu, dpdv) = if self.mesh.uvs.is_empty() {
+            (b1, b2, e1, e2)
+        } else {
+            let uv = &self.mesh.uvs;
+            let (dpdu, dpdv) = uv_tangents(&e1, &e2, uv[i0], uv[i1], uv[i2]);
+            (
+                uv[i0].0 * b0 + uv[i1].0 * b1 + uv[i2].0 * b2,
+                uv[i0].1 * b0 + uv[i1].1 * b1 + uv[i2].1 * b2,
+                dpdu,
+                dpdv,
+            )
+        };
+        let mut rec = HitRecord::new(
+            t,
+            r.point_at_parameter(t),
+            normal,
+            self.mesh.material(self.face),
+            u,
+            v,
+        )
+        .with_tangents(dpdu, dpdv);
+        if !self.mesh.colors.is_empty() {
+            let c = &self.mesh.colors;
+            rec.vertex_color = Some(c[i0] * b0 + c[i1] * b1 + c[i2] * b2);
+        }
+        Some(rec)
+    }
+
+    pub fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<Aabb> {
+        let (i0, i1, i2) = self.vertices();
+        let mut min = Vec3(f32::MAX, f32::MAX, f32::MAX);
+        let mut max = Vec3(f32::MIN, f32::MIN, f32::MIN);
+        for &i in &[i0, i1, i2] {
+            let p = self.mesh.positions[i];
+            for c in 0..3 {
+                min[c] = min[c].min(p[c]);
+                max[c] = max[c].max(p[c]);
+            }
+        }
+        // Axis aligned triangles would otherwise get a box without volume.
+        for c in 0..3 {
+            if max[c] - min[c] < 0.0002 {
+                min[c] -= 0.0001;
+                max[c] += 0.0001;
+            }
+        }
+        Some(Aabb::new(min, max))
+    }
+}
+
+// Solves e1 = dpdu * du1 + dpdv * dv1 and e2 = dpdu * du2 + dpdv * dv2 for
+// the edges of a triangle and the uv differences along them. Zero when the
+// uvs don't span an area.
+fn uv_tangents(
+    e1: &Vec3,
+    e2: &Vec3,
+    uv0: (f32, f32),
+    uv1: (f32, f32),
+    uv2: (f32, f32),
+) -> (Vec3, Vec3) {
+    let (du1, dv1) = (uv1.0 - uv0.0, uv1.1 - uv0.1);
+    let (du2, dv2) = (uv2.0 - uv0.0, uv2.1 - uv0.1);
+    let det = du1 * dv2 - dv1 * du2;
+    if det.abs() < 1e-12 {
+        return (Vec3(0.0, 0.0, 0.0), Vec3(0.0, 0.0, 0.0));
+    }
+    let inv_det = 1.0 / det;
+    (
+        (*e1 * dv2 - *e2 * dv1) * inv_det,
+        (*e2 * du1 - *e1 * du2) * inv_det,
+    )
+}
+
+// All faces of a mesh behind their own bvh, so the whole mesh can be placed,
+// rotated or instanced as a single Hitable.
+#[derive(Clone, Debug)]
+pub struct TriangleMesh {
+    bvh: FlatBvh,
+}
+
+impl TriangleMesh {
+    pub fn new(mesh: Mesh) -> TriangleMesh {
+        let mesh = Arc::new(mesh);
+        TriangleMesh {
+            bvh: FlatBvh::new(triangles(&mesh), 0.0, 1.0),
+        }
+    }
+
+    pub fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        self.bvh.hit(r, t_min, t_max)
+    }
+
+    pub fn bounding_box(&self, t0: f32, t1: f32) -> Option<Aabb> {
+        self.bvh.bounding_box(t0, t1)
+    }
+}
+
+pub fn triangles(mesh: &Arc<Mesh>) -> Vec<Hitable> {
+    (0..mesh.indices.len())
+        .map(|face| Hitable::Triangle(Triangle::from_mesh(mesh.clone(), face)))
+        .collect()
+}
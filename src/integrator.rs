@@ -0,0 +1,194 @@
+use std::f32;
+
+use vector::*;
+use ray::*;
+use hitable::*;
+use light::*;
+use sampler::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Integrator {
+    Path,
+    Nee,
+    Mis,
+}
+
+impl Integrator {
+    pub fn from_name(name: &str) -> Option<Integrator> {
+        match name {
+            "path" => Some(Integrator::Path),
+            "nee" => Some(Integrator::Nee),
+            "mis" => Some(Integrator::Mis),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Integrator::Path => "path",
+            Integrator::Nee => "nee",
+            Integrator::Mis => "mis",
+        }
+    }
+
+    // Follows one path from the camera ray. Every integrator shares the loop
+    // and only differs in how lights are found: path waits for a scattered
+    // ray to hit one, nee samples a light at every non specular bounce and
+    // ignores lights hit right after, and mis does both and weights them with
+    // the power heuristic.
+    pub fn color(
+        &self,
+        r: &Ray,
+        world: &Hitable,
+        lights: &LightList,
+        limits: &PathLimits,
+        sampler: &mut Sampler,
+    ) -> Vec3 {
+        let mut result = Vec3(0.0, 0.0, 0.0);
+        let mut throughput = Vec3(1.0, 1.0, 1.0);
+        let mut ray = Ray::new(r.origin(), r.direction(), r.time());
+        // Position and pdf of the last bounce, None for the camera ray and
+        // after delta lobes which a light sample can never reproduce.
+        let mut previous: Option<(Vec3, f32)> = None;
+        let mut depth = 0;
+        while let Some(rec) = world.hit(&ray, 0.001, f32::MAX) {
+            throughput = throughput * rec.material.transmittance(&ray, &rec);
+            let emitted = rec.material.emitted(rec.u, rec.v, &rec.p);
+            let weight = match (*self, previous) {
+                (Integrator::Path, _) | (_, None) => 1.0,
+                (Integrator::Nee, Some(_)) => {
+                    if lights.contains(&rec) {
+                        0.0
+                    } else {
+                        1.0
+                    }
+                }
+                (Integrator::Mis, Some((origin, bsdf_pdf))) => {
+                    let light_pdf = lights.pdf(&origin, &rec);
+                    if light_pdf > 0.0 {
+                        power_heuristic(bsdf_pdf, light_pdf)
+                    } else {
+                        1.0
+                    }
+                }
+            };
+            // Light found by a scattered ray has bounced once less than the
+            // path, so only from the second bounce on is it indirect.
+            let contribution = throughput * emitted * weight;
+            result = result + limits.clamp(contribution, depth >= 2);
+            if depth >= limits.max_depth {
+                break;
+            }
+            // Direct light doesn't depend on the scattered ray, which may well
+            // be absorbed.
+            if *self != Integrator::Path && !rec.material.is_specular() {
+                sampler.start_bounce(depth, LIGHT_SLOT);
+                let mis = *self == Integrator::Mis;
+                let contribution =
+                    throughput * direct_light(&ray, &rec, world, lights, mis, sampler);
+                result = result + limits.clamp(contribution, depth >= 1);
+            }
+            sampler.start_bounce(depth, BSDF_SLOT);
+            let sample = match rec.material.sample(&ray, &rec, sampler) {
+                Some(sample) => sample,
+                None => break,
+            };
+            throughput = throughput * sample.weight;
+            previous = if sample.delta {
+                None
+            } else {
+                Some((rec.p, sample.pdf))
+            };
+            sampler.start_bounce(depth, ROULETTE_SLOT);
+            depth += 1;
+            let roulette = &limits.roulette;
+            if depth >= roulette.depth {
+                let survival = throughput
+                    .x()
+                    .max(throughput.y())
+                    .max(throughput.z())
+                    .max(roulette.min_survival)
+                    .min(1.0);
+                if sampler.next_1d() >= survival {
+                    break;
+                }
+                throughput = throughput / survival;
+            }
+            ray = Ray::new(rec.p, sample.direction, ray.time());
+        }
+        result
+    }
+}
+
+// Russian roulette: from this many bounces on, paths are ended with a
+// probability that grows as their throughput drops, and the survivors are
+// scaled up to make up for it.
+#[derive(Clone, Copy, Debug)]
+pub struct Roulette {
+    pub depth: u32,
+    pub min_survival: f32,
+}
+
+// How long paths get: at most max_depth bounces, fewer with roulette. With
+// an indirect clamp, light that reaches the camera over more than one bounce
+// is scaled down so its brightest channel doesn't exceed the clamp. That
+// loses some energy but removes the fireflies of rare bright paths, like
+// caustics through glass that a diffuse surface can't find on purpose.
+#[derive(Clone, Copy, Debug)]
+pub struct PathLimits {
+    pub max_depth: u32,
+    pub roulette: Roulette,
+    pub indirect_clamp: Option<f32>,
+}
+
+impl PathLimits {
+    fn clamp(&self, contribution: Vec3, indirect: bool) -> Vec3 {
+        let limit = match self.indirect_clamp {
+            Some(limit) if indirect => limit,
+            _ => return contribution,
+        };
+        let brightest = contribution
+            .x()
+            .max(contribution.y())
+            .max(contribution.z());
+        if brightest > limit {
+            contribution * (limit / brightest)
+        } else {
+            contribution
+        }
+    }
+}
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let a = pdf * pdf;
+    a / (a + other_pdf * other_pdf)
+}
+
+// One light sample, weighted against scattering when mis is set.
+fn direct_light(
+    r: &Ray,
+    rec: &HitRecord<'_>,
+    world: &Hitable,
+    lights: &LightList,
+    mis: bool,
+    sampler: &mut Sampler,
+) -> Vec3 {
+    let sample = match lights.sample(&rec.p, sampler) {
+        Some(sample) => sample,
+        None => return Vec3(0.0, 0.0, 0.0),
+    };
+    let f = rec.material.eval(r, rec, &sample.direction);
+    if f.x() <= 0.0 && f.y() <= 0.0 && f.z() <= 0.0 {
+        return Vec3(0.0, 0.0, 0.0);
+    }
+    let shadow = Ray::new(rec.p, sample.direction, r.time());
+    if world.hit(&shadow, 0.001, sample.distance * 0.999).is_some() {
+        return Vec3(0.0, 0.0, 0.0);
+    }
+    let weight = if mis {
+        power_heuristic(sample.pdf, rec.material.pdf(r, rec, &sample.direction))
+    } else {
+        1.0
+    };
+    f * sample.radiance * (weight / sample.pdf)
+}
@@ -0,0 +1,264 @@
+use std::f32;
+
+use vector::*;
+use hitable::{HitRecord, Hitable};
+use material::*;
+use utils::*;
+use sampler::Sampler;
+
+// A direction towards a light with the radiance arriving along it. The pdf is
+// per unit solid angle and already includes picking this light.
+pub struct LightSample {
+    pub direction: Vec3,
+    pub distance: f32,
+    pub pdf: f32,
+    pub radiance: Vec3,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Light<'a> {
+    Rect(RectLight<'a>),
+    Sphere(SphereLight<'a>),
+}
+
+impl<'a> Light<'a> {
+    fn sample(&self, p: &Vec3, sampler: &mut Sampler) -> Option<LightSample> {
+        match *self {
+            Light::Rect(ref rect) => rect.sample(p, sampler),
+            Light::Sphere(ref sphere) => sphere.sample(p, sampler),
+        }
+    }
+
+    fn pdf(&self, origin: &Vec3, point: &Vec3) -> f32 {
+        match *self {
+            Light::Rect(ref rect) => rect.pdf(origin, point),
+            Light::Sphere(ref sphere) => sphere.pdf(origin, point),
+        }
+    }
+
+    // The shape_id of the primitive the light was made from.
+    fn shape(&self) -> usize {
+        match *self {
+            Light::Rect(ref rect) => rect.shape,
+            Light::Sphere(ref sphere) => sphere.shape,
+        }
+    }
+}
+
+// Axis aligned rectangle spanned by two edges from a corner. The edges run
+// along the u and v texture coordinates of the rect it came from.
+#[derive(Clone, Copy, Debug)]
+pub struct RectLight<'a> {
+    corner: Vec3,
+    edge_u: Vec3,
+    edge_v: Vec3,
+    material: &'a Material,
+    shape: usize,
+}
+
+impl<'a> RectLight<'a> {
+    pub fn new(
+        corner: Vec3,
+        edge_u: Vec3,
+        edge_v: Vec3,
+        material: &'a Material,
+        shape: usize,
+    ) -> RectLight<'a> {
+        RectLight {
+            corner,
+            edge_u,
+            edge_v,
+            material,
+            shape,
+        }
+    }
+
+    fn sample(&self, p: &Vec3, sampler: &mut Sampler) -> Option<LightSample> {
+        let (u, v) = sampler.next_2d();
+        let point = self.corner + self.edge_u * u + self.edge_v * v;
+        let normal = cross(&self.edge_u, &self.edge_v);
+        let area = normal.length();
+        let to_light = point - *p;
+        let distance_squared = to_light.squared_length();
+        let distance = distance_squared.sqrt();
+        let direction = to_light / distance;
+        // Diffuse lights emit from both sides.
+        let cosine = (dot(&normal, &direction) / area).abs();
+        if cosine < 1e-6 || distance < 1e-6 {
+            return None;
+        }
+        Some(LightSample {
+            direction,
+            distance,
+            pdf: distance_squared / (cosine * area),
+            radiance: self.material.emitted(u, v, &point),
+        })
+    }
+
+    fn pdf(&self, origin: &Vec3, point: &Vec3) -> f32 {
+        let normal = cross(&self.edge_u, &self.edge_v);
+        let area = normal.length();
+        let to_light = *point - *origin;
+        let distance_squared = to_light.squared_length();
+        let cosine = (dot(&normal, &to_light) / (area * distance_squared.sqrt())).abs();
+        if cosine < 1e-6 {
+            return 0.0;
+        }
+        distance_squared / (cosine * area)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SphereLight<'a> {
+    center: Vec3,
+    radius: f32,
+    material: &'a Material,
+    shape: usize,
+}
+
+impl<'a> SphereLight<'a> {
+    pub fn new(center: Vec3, radius: f32, material: &'a Material, shape: usize) -> SphereLight<'a> {
+        SphereLight {
+            center,
+            radius,
+            material,
+            shape,
+        }
+    }
+
+    // Samples the cone of directions the sphere covers as seen from p, or
+    // the whole surface by area from inside the sphere.
+    fn sample(&self, p: &Vec3, sampler: &mut Sampler) -> Option<LightSample> {
+        let u = sampler.next_2d();
+        let to_center = self.center - *p;
+        let center_distance_squared = to_center.squared_length();
+        let radius_squared = self.radius * self.radius;
+        if center_distance_squared <= radius_squared {
+            let outward = sample_unit_vector(u);
+            let point = self.center + outward * self.radius;
+            let to_light = point - *p;
+            let distance_squared = to_light.squared_length();
+            let distance = distance_squared.sqrt();
+            let direction = to_light / distance;
+            let cosine = dot(&outward, &direction).abs();
+            if cosine < 1e-6 || distance < 1e-6 {
+                return None;
+            }
+            let area = 4.0 * f32::consts::PI * radius_squared;
+            return Some(LightSample {
+                direction,
+                distance,
+                pdf: distance_squared / (cosine * area),
+                radiance: self.emitted(&point),
+            });
+        }
+        let center_distance = center_distance_squared.sqrt();
+        let axis = to_center / center_distance;
+        let cos_theta_max = (1.0 - radius_squared / center_distance_squared)
+            .max(0.0)
+            .sqrt();
+        let cos_theta = 1.0 - u.0 * (1.0 - cos_theta_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * f32::consts::PI * u.1;
+        let (tangent, bitangent) = orthonormal_basis(&axis);
+        let direction = tangent * (sin_theta * phi.cos())
+            + bitangent * (sin_theta * phi.sin())
+            + axis * cos_theta;
+        // Nearest intersection along the direction, clamped for directions
+        // that graze the silhouette.
+        let half_chord = (radius_squared - center_distance_squared * sin_theta * sin_theta)
+            .max(0.0)
+            .sqrt();
+        let distance = center_distance * cos_theta - half_chord;
+        let solid_angle = 2.0 * f32::consts::PI * (1.0 - cos_theta_max);
+        if solid_angle <= 0.0 || distance <= 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            direction,
+            distance,
+            pdf: 1.0 / solid_angle,
+            radiance: self.emitted(&(*p + direction * distance)),
+        })
+    }
+
+    fn pdf(&self, origin: &Vec3, point: &Vec3) -> f32 {
+        let center_distance_squared = (self.center - *origin).squared_length();
+        let radius_squared = self.radius * self.radius;
+        if center_distance_squared <= radius_squared {
+            let to_light = *point - *origin;
+            let distance_squared = to_light.squared_length();
+            let outward = (*point - self.center) / self.radius;
+            let cosine = (dot(&outward, &to_light) / distance_squared.sqrt()).abs();
+            if cosine < 1e-6 {
+                return 0.0;
+            }
+            return distance_squared / (cosine * 4.0 * f32::consts::PI * radius_squared);
+        }
+        let cos_theta_max = (1.0 - radius_squared / center_distance_squared)
+            .max(0.0)
+            .sqrt();
+        let solid_angle = 2.0 * f32::consts::PI * (1.0 - cos_theta_max);
+        if solid_angle <= 0.0 {
+            return 0.0;
+        }
+        1.0 / solid_angle
+    }
+
+    fn emitted(&self, point: &Vec3) -> Vec3 {
+        let (u, v) = get_sphere_uv(&((*point - self.center) / self.radius));
+        self.material.emitted(u, v, point)
+    }
+}
+
+// Every emitter in the world that can be sampled directly. Lights the list
+// can't describe, like rotated rects or meshes, are still found by following
+// scattered rays.
+pub struct LightList<'a> {
+    lights: Vec<Light<'a>>,
+}
+
+impl<'a> LightList<'a> {
+    pub fn new(world: &'a Hitable) -> LightList<'a> {
+        let mut lights = Vec::new();
+        world.collect_lights(Vec3(0.0, 0.0, 0.0), &mut lights);
+        LightList { lights }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn sample(&self, p: &Vec3, sampler: &mut Sampler) -> Option<LightSample> {
+        if self.lights.is_empty() {
+            return None;
+        }
+        let index =
+            ((sampler.next_1d() * self.lights.len() as f32) as usize).min(self.lights.len() - 1);
+        self.lights[index].sample(p, sampler).map(|mut sample| {
+            sample.pdf /= self.lights.len() as f32;
+            sample
+        })
+    }
+
+    // Density with which sample would have picked the direction from origin
+    // to the hit, zero if the hit isn't on a light in the list.
+    pub fn pdf(&self, origin: &Vec3, rec: &HitRecord<'_>) -> f32 {
+        match self.find(rec) {
+            Some(light) => light.pdf(origin, &rec.p) / self.lights.len() as f32,
+            None => 0.0,
+        }
+    }
+
+    // Whether light from this hit is already accounted for by sampling.
+    pub fn contains(&self, rec: &HitRecord<'_>) -> bool {
+        self.find(rec).is_some()
+    }
+
+    fn find(&self, rec: &HitRecord<'_>) -> Option<&Light<'a>> {
+        if rec.shape == 0 {
+            return None;
+        }
+        self.lights.iter().find(|light| light.shape() == rec.shape)
+    }
+}
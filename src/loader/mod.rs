@@ -0,0 +1,61 @@
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub mod obj;
+pub mod ply;
+pub mod stl;
+
+use hitable::Mesh;
+use material::Material;
+pub use self::obj::*;
+pub use self::ply::*;
+pub use self::stl::*;
+
+#[derive(Debug)]
+pub struct LoadError {
+    pub path: PathBuf,
+    pub line: usize,
+    pub message: String,
+}
+
+impl LoadError {
+    pub fn new(path: &Path, line: usize, message: String) -> LoadError {
+        LoadError {
+            path: path.to_path_buf(),
+            line,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.path.display(), self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.path.display(), self.line, self.message)
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+// Picks the loader from the file extension. Files without materials of their
+// own use default_material for every face.
+pub fn load_mesh(path: &Path, default_material: Material) -> Result<Mesh, LoadError> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    match extension.as_deref() {
+        Some("obj") => load_obj(path, default_material),
+        Some("ply") => load_ply(path, default_material),
+        Some("stl") => load_stl(path, default_material),
+        _ => Err(LoadError::new(
+            path,
+            0,
+            "unsupported mesh format, expected .obj, .ply or .stl".to_string(),
+        )),
+    }
+}
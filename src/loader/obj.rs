@@ -0,0 +1,610 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::iter;
+use std::path::Path;
+use std::str::SplitWhitespace;
+
+use hitable::Mesh;
+use loader::LoadError;
+use material::*;
+use texture::*;
+use utils::*;
+use vector::Vec3;
+
+// Loads a Wavefront OBJ file as a single mesh. Polygons are triangulated as
+// fans and materials from `mtllib` files are mapped onto the closest material
+// this renderer has. Faces before the first `usemtl` get default_material.
+pub fn load_obj(path: &Path, default_material: Material) -> Result<Mesh, LoadError> {
+    parse_obj(&read_source(path)?, path, default_material)
+}
+
+// The OBJ source read from path, which material libraries are relative to.
+fn parse_obj(source: &str, path: &Path, default_material: Material) -> Result<Mesh, LoadError> {
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut parser = ObjParser::new(default_material);
+    for (index, line) in source.lines().enumerate() {
+        parser
+            .parse_line(line, base_dir)
+            .map_err(|err| err.at(path, index + 1))?;
+    }
+    let mesh = parser.finish();
+    mesh.validate()
+        .map_err(|message| LoadError::new(path, 0, message))?;
+    Ok(mesh)
+}
+
+pub fn load_mtl(path: &Path) -> Result<HashMap<String, Material>, LoadError> {
+    parse_mtl(&read_source(path)?, path)
+}
+
+// The MTL source read from path, which texture maps are relative to.
+fn parse_mtl(source: &str, path: &Path) -> Result<HashMap<String, Material>, LoadError> {
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+    for (index, line) in source.lines().enumerate() {
+        let mut tokens = Tokens::new(line);
+        let result = match tokens.next() {
+            None => Ok(()),
+            Some("newmtl") => tokens.rest("material name").map(|name| {
+                if let Some((name, material)) = current.take() {
+                    materials.insert(name, material.to_material());
+                }
+                current = Some((name, MtlMaterial::new()));
+            }),
+            Some(keyword) => match current {
+                Some((_, ref mut material)) => material.parse(keyword, &mut tokens, base_dir),
+                None => Err(format!("`{}` before the first `newmtl`", keyword)),
+            },
+        };
+        result.map_err(|message| LoadError::new(path, index + 1, message))?;
+    }
+    if let Some((name, material)) = current.take() {
+        materials.insert(name, material.to_material());
+    }
+    Ok(materials)
+}
+
+fn read_source(path: &Path) -> Result<String, LoadError> {
+    let mut source = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut source))
+        .map_err(|err| LoadError::new(path, 0, err.to_string()))?;
+    Ok(source)
+}
+
+// Errors inside an mtl library already carry their own file and line.
+enum ObjError {
+    Message(String),
+    Library(LoadError),
+}
+
+impl ObjError {
+    fn at(self, path: &Path, line: usize) -> LoadError {
+        match self {
+            ObjError::Message(message) => LoadError::new(path, line, message),
+            ObjError::Library(err) => err,
+        }
+    }
+}
+
+impl From<String> for ObjError {
+    fn from(message: String) -> ObjError {
+        ObjError::Message(message)
+    }
+}
+
+struct Tokens<'a> {
+    line: &'a str,
+    iter: SplitWhitespace<'a>,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(line: &'a str) -> Tokens<'a> {
+        let line = match line.find('#') {
+            Some(index) => &line[..index],
+            None => line,
+        };
+        Tokens {
+            line,
+            iter: line.split_whitespace(),
+        }
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        self.iter.next()
+    }
+
+    fn float(&mut self, what: &str) -> Result<f32, String> {
+        let token = self
+            .iter
+            .next()
+            .ok_or_else(|| format!("expected {}", what))?;
+        token
+            .parse::<f32>()
+            .map_err(|_| format!("expected {}, found `{}`", what, token))
+    }
+
+    fn vec3(&mut self, what: &str) -> Result<Vec3, String> {
+        Ok(Vec3(
+            self.float(what)?,
+            self.float(what)?,
+            self.float(what)?,
+        ))
+    }
+
+    // Names and file names may contain spaces, so they take the rest of the line.
+    fn rest(&mut self, what: &str) -> Result<String, String> {
+        let first = self
+            .iter
+            .next()
+            .ok_or_else(|| format!("expected {}", what))?;
+        let start = first.as_ptr() as usize - self.line.as_ptr() as usize;
+        self.iter = "".split_whitespace();
+        Ok(self.line[start..].trim().to_string())
+    }
+}
+
+type VertexKey = (usize, Option<usize>, Option<usize>);
+
+struct ObjParser {
+    positions: Vec<Vec3>,
+    texcoords: Vec<(f32, f32)>,
+    normals: Vec<Vec3>,
+    library: HashMap<String, Material>,
+    material_ids: HashMap<String, u32>,
+    current_material: u32,
+    vertex_ids: HashMap<VertexKey, u32>,
+    mesh_positions: Vec<Vec3>,
+    mesh_uvs: Vec<Option<(f32, f32)>>,
+    mesh_normals: Vec<Option<Vec3>>,
+    indices: Vec<[u32; 3]>,
+    materials: Vec<Material>,
+    face_materials: Vec<u32>,
+}
+
+impl ObjParser {
+    fn new(default_material: Material) -> ObjParser {
+        ObjParser {
+            positions: Vec::new(),
+            texcoords: Vec::new(),
+            normals: Vec::new(),
+            library: HashMap::new(),
+            material_ids: HashMap::new(),
+            current_material: 0,
+            vertex_ids: HashMap::new(),
+            mesh_positions: Vec::new(),
+            mesh_uvs: Vec::new(),
+            mesh_normals: Vec::new(),
+            indices: Vec::new(),
+            materials: vec![default_material],
+            face_materials: Vec::new(),
+        }
+    }
+
+    fn parse_line(&mut self, line: &str, base_dir: &Path) -> Result<(), ObjError> {
+        let mut tokens = Tokens::new(line);
+        match tokens.next() {
+            Some("v") => self.positions.push(tokens.vec3("vertex position")?),
+            Some("vt") => {
+                let u = tokens.float("texture coordinate")?;
+                let v = match tokens.next() {
+                    Some(token) => token
+                        .parse::<f32>()
+                        .map_err(|_| format!("expected texture coordinate, found `{}`", token))?,
+                    None => 0.0,
+                };
+                self.texcoords.push((u, v));
+            }
+            Some("vn") => self.normals.push(tokens.vec3("vertex normal")?),
+            Some("f") => self.parse_face(&mut tokens)?,
+            // One line may list several libraries, so unlike other names
+            // theirs can't contain spaces.
+            Some("mtllib") => {
+                let first = tokens
+                    .next()
+                    .ok_or_else(|| "expected material library".to_string())?;
+                for file in iter::once(first).chain(tokens.iter.by_ref()) {
+                    let library = load_mtl(&base_dir.join(file)).map_err(ObjError::Library)?;
+                    self.library.extend(library);
+                }
+            }
+            Some("usemtl") => {
+                let name = tokens.rest("material name")?;
+                self.current_material = self.material_id(&name)?;
+            }
+            // Groups, objects, smoothing groups and the rest of the format
+            // have no effect on how the mesh renders.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn material_id(&mut self, name: &str) -> Result<u32, String> {
+        if let Some(&id) = self.material_ids.get(name) {
+            return Ok(id);
+        }
+        let material = self
+            .library
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("unknown material `{}`", name))?;
+        let id = self.materials.len() as u32;
+        self.materials.push(material);
+        self.material_ids.insert(name.to_string(), id);
+        Ok(id)
+    }
+
+    fn parse_face(&mut self, tokens: &mut Tokens) -> Result<(), String> {
+        let mut face = Vec::new();
+        while let Some(token) = tokens.next() {
+            face.push(self.vertex(token)?);
+        }
+        if face.len() < 3 {
+            return Err(format!(
+                "face needs at least 3 vertices, found {}",
+                face.len()
+            ));
+        }
+        for i in 1..face.len() - 1 {
+            self.indices.push([face[0], face[i], face[i + 1]]);
+            self.face_materials.push(self.current_material);
+        }
+        Ok(())
+    }
+
+    fn vertex(&mut self, token: &str) -> Result<u32, String> {
+        let mut parts = token.split('/');
+        let position = resolve_index(parts.next(), self.positions.len(), "vertex")?
+            .ok_or_else(|| format!("face vertex `{}` has no position", token))?;
+        let texcoord = resolve_index(parts.next(), self.texcoords.len(), "texture coordinate")?;
+        let normal = resolve_index(parts.next(), self.normals.len(), "normal")?;
+        if parts.next().is_some() {
+            return Err(format!("malformed face vertex `{}`", token));
+        }
+        let key = (position, texcoord, normal);
+        if let Some(&id) = self.vertex_ids.get(&key) {
+            return Ok(id);
+        }
+        let id = self.mesh_positions.len() as u32;
+        self.mesh_positions.push(self.positions[position]);
+        self.mesh_uvs.push(texcoord.map(|i| self.texcoords[i]));
+        self.mesh_normals.push(normal.map(|i| self.normals[i]));
+        self.vertex_ids.insert(key, id);
+        Ok(id)
+    }
+
+    fn finish(self) -> Mesh {
+        // Uvs default to the origin for vertices without one, normals are only
+        // used when every vertex has one.
+        let uvs = if self.mesh_uvs.iter().any(|uv| uv.is_some()) {
+            self.mesh_uvs
+                .iter()
+                .map(|uv| uv.unwrap_or((0.0, 0.0)))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let normals = if self.mesh_normals.iter().all(|normal| normal.is_some()) {
+            self.mesh_normals.into_iter().flatten().collect()
+        } else {
+            Vec::new()
+        };
+        let face_materials = if self.materials.len() > 1 {
+            self.face_materials
+        } else {
+            Vec::new()
+        };
+        Mesh {
+            positions: self.mesh_positions,
+            normals,
+            uvs,
+            colors: Vec::new(),
+            indices: self.indices,
+            materials: self.materials,
+            face_materials,
+        }
+    }
+}
+
+fn resolve_index(token: Option<&str>, count: usize, what: &str) -> Result<Option<usize>, String> {
+    let token = match token {
+        None | Some("") => return Ok(None),
+        Some(token) => token,
+    };
+    let index = token
+        .parse::<i64>()
+        .map_err(|_| format!("invalid {} index `{}`", what, token))?;
+    let resolved = if index > 0 {
+        index - 1
+    } else {
+        count as i64 + index
+    };
+    if index == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(format!("{} index {} out of range", what, index));
+    }
+    Ok(Some(resolved as usize))
+}
+
+struct MtlMaterial {
+    kd: Vec3,
+    ks: Vec3,
+    ke: Vec3,
+    ns: f32,
+    ni: f32,
+    dissolve: f32,
+    illum: u32,
+    map_kd: Option<Texture>,
+}
+
+impl MtlMaterial {
+    fn new() -> MtlMaterial {
+        MtlMaterial {
+            kd: Vec3(0.8, 0.8, 0.8),
+            ks: Vec3(0.0, 0.0, 0.0),
+            ke: Vec3(0.0, 0.0, 0.0),
+            ns: 0.0,
+            ni: 1.5,
+            dissolve: 1.0,
+            illum: 2,
+            map_kd: None,
+        }
+    }
+
+    fn parse(&mut self, keyword: &str, tokens: &mut Tokens, base_dir: &Path) -> Result<(), String> {
+        match keyword {
+            "Kd" => self.kd = tokens.vec3("diffuse color")?,
+            "Ks" => self.ks = tokens.vec3("specular color")?,
+            "Ke" => self.ke = tokens.vec3("emissive color")?,
+            "Ns" => self.ns = tokens.float("specular exponent")?,
+            "Ni" => self.ni = tokens.float("refraction index")?,
+            "d" => self.dissolve = tokens.float("dissolve")?,
+            "Tr" => self.dissolve = 1.0 - tokens.float("transparency")?,
+            "illum" => {
+                let token = tokens.next().ok_or("expected illumination model")?;
+                self.illum = token
+                    .parse::<u32>()
+                    .map_err(|_| format!("expected illumination model, found `{}`", token))?;
+            }
+            "map_Kd" => {
+                let line = tokens.rest("texture file")?;
+                let file = texture_file(&line);
+                if file.is_empty() {
+                    return Err("expected texture file".to_string());
+                }
+                let texture = ImageTexture::load(&base_dir.join(file))?;
+                self.map_kd = Some(Texture::ImageTexture(texture));
+            }
+            // Other maps and parameters are not supported by the materials.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn to_material(&self) -> Material {
+        if !is_black(&self.ke) {
+            return new_diffuce(new_constant_texture(self.ke));
+        }
+        if self.dissolve < 1.0 || self.illum == 4 || self.illum == 6 || self.illum == 7 {
+            return Material::Dielectric(Dielectric::new(self.ni));
+        }
+        if !is_black(&self.ks) && (self.illum == 3 || self.illum == 5 || is_black(&self.kd)) {
+            // Map the Phong exponent onto a roughness like fuzz.
+            let fuzz = (2.0 / (self.ns.max(0.0) + 2.0)).sqrt();
+            return Material::Metal(Metal::new(self.ks, fuzz));
+        }
+        match self.map_kd {
+            Some(ref texture) => new_labertian(texture.clone()),
+            None => new_labertian(new_constant_texture(self.kd)),
+        }
+    }
+}
+
+// The file name of a texture map after the options that may come before it,
+// like `-s 2 2 1` or `-clamp on`. Option values are numbers, on or off, but
+// -imfchan and -type take a single word. The name is the rest of the line.
+fn texture_file(line: &str) -> &str {
+    let mut rest = line.trim();
+    while rest.starts_with('-') {
+        let (option, mut after) = split_word(rest);
+        if option == "-imfchan" || option == "-type" {
+            after = split_word(after).1;
+        } else {
+            loop {
+                let (value, next) = split_word(after);
+                if value.is_empty()
+                    || !(value == "on" || value == "off" || value.parse::<f32>().is_ok())
+                {
+                    break;
+                }
+                after = next;
+            }
+        }
+        rest = after;
+    }
+    rest
+}
+
+// The first word of text and the text after it, without leading whitespace.
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+fn is_black(color: &Vec3) -> bool {
+    color.r() <= 0.0 && color.g() <= 0.0 && color.b() <= 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn grey() -> Material {
+        new_labertian(new_constant_texture(Vec3(0.5, 0.5, 0.5)))
+    }
+
+    fn obj(source: &str) -> Result<Mesh, LoadError> {
+        parse_obj(source, Path::new("test.obj"), grey())
+    }
+
+    fn mtl(source: &str) -> HashMap<String, Material> {
+        parse_mtl(source, Path::new("test.mtl")).unwrap()
+    }
+
+    // A directory of its own for a test that needs files on disk.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("obj-test-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const SQUARE: &str = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n";
+
+    #[test]
+    fn triangulates_polygons_as_fans() {
+        let mesh = obj(&format!("{}f 1 2 3 4\n", SQUARE)).unwrap();
+        assert_eq!(mesh.positions.len(), 4);
+        assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3]]);
+        assert!(mesh.normals.is_empty());
+        assert!(mesh.uvs.is_empty());
+        assert!(mesh.face_materials.is_empty());
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_last_vertex() {
+        let mesh = obj(&format!("{}f -4 -3 -2\nv 5 5 5\nf -1 -2 -3\n", SQUARE)).unwrap();
+        assert_eq!(mesh.indices.len(), 2);
+        let corners = |face: [u32; 3]| -> Vec<(f32, f32, f32)> {
+            face.iter()
+                .map(|&i| mesh.positions[i as usize])
+                .map(|p| (p.x(), p.y(), p.z()))
+                .collect()
+        };
+        assert_eq!(
+            corners(mesh.indices[0]),
+            vec![(0.0, 0.0, 0.0), (1.0, 0.0, 0.0), (1.0, 1.0, 0.0)]
+        );
+        assert_eq!(
+            corners(mesh.indices[1]),
+            vec![(5.0, 5.0, 5.0), (0.0, 1.0, 0.0), (1.0, 1.0, 0.0)]
+        );
+    }
+
+    #[test]
+    fn shares_vertices_with_the_same_attributes() {
+        let source = format!(
+            "{}vt 0 0\nvt 1 1\nvn 0 0 1\n\
+             f 1/1/1 2/2/1 3/1/1\nf 1/1/1 3/1/1 4/2/1\nf 1/2/1 2/2/1 3/2/1\n",
+            SQUARE
+        );
+        let mesh = obj(&source).unwrap();
+        // 1/1/1 and 3/1/1 are shared, 1/2/1 and 3/2/1 are new vertices.
+        assert_eq!(mesh.positions.len(), 6);
+        assert_eq!(mesh.uvs.len(), 6);
+        assert_eq!(mesh.normals.len(), 6);
+        assert_eq!(mesh.indices[1], [0, 2, 3]);
+    }
+
+    #[test]
+    fn out_of_range_indices_report_file_and_line() {
+        let err = obj(&format!("{}\nf 1 2 5\n", SQUARE)).unwrap_err();
+        assert_eq!(err.to_string(), "test.obj:6: vertex index 5 out of range");
+        let err = obj(&format!("{}f 1 2 -5\n", SQUARE)).unwrap_err();
+        assert_eq!(err.to_string(), "test.obj:5: vertex index -5 out of range");
+        let err = obj(&format!("{}f 0 1 2\n", SQUARE)).unwrap_err();
+        assert_eq!(err.to_string(), "test.obj:5: vertex index 0 out of range");
+        let err = obj(&format!("{}vt 0 0\nf 1/2 2/1 3/1\n", SQUARE)).unwrap_err();
+        assert_eq!(err.to_string(), "test.obj:6: texture coordinate index 2 out of range");
+    }
+
+    #[test]
+    fn malformed_faces_are_errors() {
+        let err = obj(&format!("{}f 1 2\n", SQUARE)).unwrap_err();
+        assert_eq!(err.to_string(), "test.obj:5: face needs at least 3 vertices, found 2");
+        let err = obj(&format!("{}f 1 2 x\n", SQUARE)).unwrap_err();
+        assert_eq!(err.to_string(), "test.obj:5: invalid vertex index `x`");
+        let err = obj("v 0 0\n").unwrap_err();
+        assert_eq!(err.to_string(), "test.obj:1: expected vertex position");
+    }
+
+    #[test]
+    fn unknown_materials_are_errors() {
+        let err = obj(&format!("{}usemtl missing\nf 1 2 3\n", SQUARE)).unwrap_err();
+        assert_eq!(err.to_string(), "test.obj:5: unknown material `missing`");
+    }
+
+    #[test]
+    fn faces_use_the_material_set_before_them() {
+        let dir = scratch_dir("usemtl");
+        fs::write(dir.join("box.mtl"), "newmtl shiny metal\nKs 0.9 0.9 0.9\nillum 3\n").unwrap();
+        let source = format!(
+            "mtllib box.mtl\n{}f 1 2 3\nusemtl shiny metal\nf 1 3 4\n",
+            SQUARE
+        );
+        let mesh = parse_obj(&source, &dir.join("box.obj"), grey()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(mesh.face_materials, vec![0, 1]);
+        assert_eq!(mesh.materials.len(), 2);
+        assert!(matches!(mesh.materials[0], Material::Labertian(_)));
+        assert!(matches!(mesh.materials[1], Material::Metal(_)));
+    }
+
+    #[test]
+    fn mtllib_lines_may_list_several_libraries() {
+        let dir = scratch_dir("mtllib");
+        fs::write(dir.join("metal.mtl"), "newmtl shiny\nKs 0.9 0.9 0.9\nillum 3\n").unwrap();
+        fs::write(dir.join("glass.mtl"), "newmtl clear\nNi 1.5\nd 0.2\n").unwrap();
+        let source = format!(
+            "mtllib metal.mtl glass.mtl\n{}usemtl shiny\nf 1 2 3\nusemtl clear\nf 1 3 4\n",
+            SQUARE
+        );
+        let mesh = parse_obj(&source, &dir.join("box.obj"), grey());
+        let missing = parse_obj("mtllib metal.mtl missing.mtl\n", &dir.join("box.obj"), grey());
+        fs::remove_dir_all(&dir).unwrap();
+        let mesh = mesh.unwrap();
+        assert_eq!(mesh.face_materials, vec![1, 2]);
+        assert!(matches!(mesh.materials[1], Material::Metal(_)));
+        assert!(matches!(mesh.materials[2], Material::Dielectric(_)));
+        assert!(missing.unwrap_err().to_string().contains("missing.mtl"));
+    }
+
+    #[test]
+    fn maps_mtl_parameters_onto_materials() {
+        let materials = mtl(
+            "newmtl plastic\nKd 0.8 0.1 0.1\nKs 0.5 0.5 0.5\nillum 2\n\
+             newmtl mirror\nKd 0.8 0.1 0.1\nKs 0.9 0.9 0.9\nillum 3\n\
+             newmtl chrome\nKd 0 0 0\nKs 0.9 0.9 0.9\nNs 1000\n\
+             newmtl glass\nNi 1.5\nd 0.2\n\
+             newmtl lamp\nKe 4 4 4\n",
+        );
+        assert_eq!(materials.len(), 5);
+        assert!(matches!(materials["plastic"], Material::Labertian(_)));
+        assert!(matches!(materials["mirror"], Material::Metal(_)));
+        assert!(matches!(materials["chrome"], Material::Metal(_)));
+        assert!(matches!(materials["glass"], Material::Dielectric(_)));
+        assert!(matches!(materials["lamp"], Material::DiffuceLight(_)));
+    }
+
+    #[test]
+    fn mtl_errors_report_file_and_line() {
+        let err = parse_mtl("Kd 1 1 1\n", Path::new("test.mtl")).unwrap_err();
+        assert_eq!(err.to_string(), "test.mtl:1: `Kd` before the first `newmtl`");
+        let err = parse_mtl("newmtl a\nillum x\n", Path::new("test.mtl")).unwrap_err();
+        assert_eq!(err.to_string(), "test.mtl:2: expected illumination model, found `x`");
+    }
+
+    #[test]
+    fn texture_file_names_follow_their_options() {
+        assert_eq!(texture_file("wood.png"), "wood.png");
+        assert_eq!(texture_file("my texture.png"), "my texture.png");
+        assert_eq!(texture_file("-s 2 2 1 -clamp on my texture.png"), "my texture.png");
+        assert_eq!(texture_file("-o 0.5 -imfchan r -bm 1 bump.png"), "bump.png");
+    }
+}
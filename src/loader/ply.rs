@@ -0,0 +1,538 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::{self, SplitWhitespace};
+
+use hitable::Mesh;
+use loader::LoadError;
+use material::Material;
+use vector::Vec3;
+
+// Loads a Stanford PLY file in ascii or binary little endian encoding. Vertex
+// normals, texture coordinates and colors are kept when present, polygons
+// are triangulated as fans and every other element is skipped.
+pub fn load_ply(path: &Path, material: Material) -> Result<Mesh, LoadError> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(|err| LoadError::new(path, 0, err.to_string()))?;
+    parse_ply(&data, path, material)
+}
+
+// The contents of the PLY file at path, which errors are reported for.
+fn parse_ply(data: &[u8], path: &Path, material: Material) -> Result<Mesh, LoadError> {
+    let header =
+        parse_header(data).map_err(|(line, message)| LoadError::new(path, line, message))?;
+    let body = &data[header.body_start..];
+    let mut reader = match header.format {
+        Format::Ascii => {
+            let text = str::from_utf8(body)
+                .map_err(|_| LoadError::new(path, 0, "ascii body is not valid text".to_string()))?;
+            Reader::Ascii(AsciiReader {
+                lines: text.lines(),
+                tokens: "".split_whitespace(),
+                line: header.lines,
+            })
+        }
+        Format::BinaryLittleEndian => Reader::Binary(BinaryReader {
+            data: body,
+            offset: 0,
+        }),
+    };
+    let mut mesh = Mesh::new(Vec::new(), Vec::new(), material);
+    for element in &header.elements {
+        let result = match element.name.as_str() {
+            "vertex" => read_vertices(&mut reader, element, &mut mesh),
+            "face" => read_faces(&mut reader, element, &mut mesh),
+            _ => skip_element(&mut reader, element),
+        };
+        result.map_err(|message| match reader {
+            Reader::Ascii(ref ascii) => LoadError::new(path, ascii.line, message),
+            Reader::Binary(ref binary) => LoadError::new(
+                path,
+                0,
+                format!("{} at byte {}", message, header.body_start + binary.offset),
+            ),
+        })?;
+    }
+    mesh.validate()
+        .map_err(|message| LoadError::new(path, 0, message))?;
+    Ok(mesh)
+}
+
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+}
+
+#[derive(Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Scalar, String> {
+        match name {
+            "char" | "int8" => Ok(Scalar::I8),
+            "uchar" | "uint8" => Ok(Scalar::U8),
+            "short" | "int16" => Ok(Scalar::I16),
+            "ushort" | "uint16" => Ok(Scalar::U16),
+            "int" | "int32" => Ok(Scalar::I32),
+            "uint" | "uint32" => Ok(Scalar::U32),
+            "float" | "float32" => Ok(Scalar::F32),
+            "double" | "float64" => Ok(Scalar::F64),
+            _ => Err(format!("unknown property type `{}`", name)),
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+}
+
+enum PropertyKind {
+    Scalar(Scalar),
+    List(Scalar, Scalar),
+}
+
+struct Property {
+    name: String,
+    kind: PropertyKind,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl Element {
+    fn find(&self, names: &[&str]) -> Option<usize> {
+        self.properties
+            .iter()
+            .position(|property| names.contains(&property.name.as_str()))
+    }
+}
+
+struct Header {
+    format: Format,
+    elements: Vec<Element>,
+    body_start: usize,
+    lines: usize,
+}
+
+fn parse_header(data: &[u8]) -> Result<Header, (usize, String)> {
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut offset = 0;
+    let mut line_number = 0;
+    loop {
+        let end = match data[offset..].iter().position(|&byte| byte == b'\n') {
+            Some(end) => offset + end,
+            None => return Err((line_number, "header has no `end_header`".to_string())),
+        };
+        line_number += 1;
+        let line = str::from_utf8(&data[offset..end])
+            .map_err(|_| (line_number, "header is not valid text".to_string()))?;
+        offset = end + 1;
+        let mut tokens = line.split_whitespace();
+        let keyword = tokens.next();
+        if line_number == 1 {
+            if keyword != Some("ply") {
+                return Err((line_number, "not a ply file".to_string()));
+            }
+            continue;
+        }
+        match keyword {
+            Some("format") => {
+                format = match tokens.next() {
+                    Some("ascii") => Some(Format::Ascii),
+                    Some("binary_little_endian") => Some(Format::BinaryLittleEndian),
+                    Some(other) => {
+                        return Err((line_number, format!("unsupported format `{}`", other)))
+                    }
+                    None => return Err((line_number, "expected format".to_string())),
+                }
+            }
+            Some("element") => {
+                let name = tokens
+                    .next()
+                    .ok_or((line_number, "expected element name".to_string()))?;
+                let count = tokens
+                    .next()
+                    .and_then(|count| count.parse::<usize>().ok())
+                    .ok_or((line_number, "expected element count".to_string()))?;
+                elements.push(Element {
+                    name: name.to_string(),
+                    count,
+                    properties: Vec::new(),
+                });
+            }
+            Some("property") => {
+                let element = elements
+                    .last_mut()
+                    .ok_or((line_number, "property outside of an element".to_string()))?;
+                let property =
+                    parse_property(&mut tokens).map_err(|message| (line_number, message))?;
+                element.properties.push(property);
+            }
+            Some("end_header") => break,
+            Some("comment") | Some("obj_info") | None => {}
+            Some(other) => return Err((line_number, format!("unknown header line `{}`", other))),
+        }
+    }
+    match format {
+        Some(format) => Ok(Header {
+            format,
+            elements,
+            body_start: offset,
+            lines: line_number,
+        }),
+        None => Err((line_number, "header has no format".to_string())),
+    }
+}
+
+fn parse_property(tokens: &mut SplitWhitespace) -> Result<Property, String> {
+    let kind = match tokens.next() {
+        Some("list") => {
+            let count = Scalar::parse(tokens.next().unwrap_or(""))?;
+            let item = Scalar::parse(tokens.next().unwrap_or(""))?;
+            PropertyKind::List(count, item)
+        }
+        Some(name) => PropertyKind::Scalar(Scalar::parse(name)?),
+        None => return Err("expected property type".to_string()),
+    };
+    let name = tokens.next().ok_or("expected property name")?;
+    Ok(Property {
+        name: name.to_string(),
+        kind,
+    })
+}
+
+struct AsciiReader<'a> {
+    lines: str::Lines<'a>,
+    tokens: SplitWhitespace<'a>,
+    line: usize,
+}
+
+struct BinaryReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+// Every element starts on a new line in ascii files, binary files are just
+// a stream of values.
+enum Reader<'a> {
+    Ascii(AsciiReader<'a>),
+    Binary(BinaryReader<'a>),
+}
+
+impl<'a> Reader<'a> {
+    fn start_element(&mut self) -> Result<(), String> {
+        if let Reader::Ascii(ref mut ascii) = *self {
+            loop {
+                let line = ascii.lines.next().ok_or("unexpected end of file")?;
+                ascii.line += 1;
+                ascii.tokens = line.split_whitespace();
+                if !line.trim().is_empty() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, scalar: Scalar) -> Result<f64, String> {
+        match *self {
+            Reader::Ascii(ref mut ascii) => {
+                let token = ascii.tokens.next().ok_or("element has too few values")?;
+                token
+                    .parse::<f64>()
+                    .map_err(|_| format!("expected a number, found `{}`", token))
+            }
+            Reader::Binary(ref mut binary) => {
+                let size = scalar.size();
+                if binary.offset + size > binary.data.len() {
+                    return Err("unexpected end of file".to_string());
+                }
+                let mut bytes = [0u8; 8];
+                bytes[..size].copy_from_slice(&binary.data[binary.offset..binary.offset + size]);
+                binary.offset += size;
+                let word = [bytes[0], bytes[1], bytes[2], bytes[3]];
+                let value = match scalar {
+                    Scalar::I8 => f64::from(bytes[0] as i8),
+                    Scalar::U8 => f64::from(bytes[0]),
+                    Scalar::I16 => f64::from(i16::from_le_bytes([bytes[0], bytes[1]])),
+                    Scalar::U16 => f64::from(u16::from_le_bytes([bytes[0], bytes[1]])),
+                    Scalar::I32 => f64::from(i32::from_le_bytes(word)),
+                    Scalar::U32 => f64::from(u32::from_le_bytes(word)),
+                    Scalar::F32 => f64::from(f32::from_le_bytes(word)),
+                    Scalar::F64 => f64::from_le_bytes(bytes),
+                };
+                Ok(value)
+            }
+        }
+    }
+
+    fn read_property(&mut self, kind: &PropertyKind, values: &mut Vec<f64>) -> Result<(), String> {
+        values.clear();
+        match *kind {
+            PropertyKind::Scalar(scalar) => values.push(self.read(scalar)?),
+            PropertyKind::List(count, item) => {
+                let count = self.read(count)?;
+                if count < 0.0 {
+                    return Err("negative list length".to_string());
+                }
+                for _ in 0..count as usize {
+                    values.push(self.read(item)?);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_vertices(reader: &mut Reader, element: &Element, mesh: &mut Mesh) -> Result<(), String> {
+    let position = [
+        element.find(&["x"]).ok_or("vertex has no x coordinate")?,
+        element.find(&["y"]).ok_or("vertex has no y coordinate")?,
+        element.find(&["z"]).ok_or("vertex has no z coordinate")?,
+    ];
+    let normal = match (
+        element.find(&["nx"]),
+        element.find(&["ny"]),
+        element.find(&["nz"]),
+    ) {
+        (Some(x), Some(y), Some(z)) => Some([x, y, z]),
+        _ => None,
+    };
+    let uv = match (
+        element.find(&["u", "s", "texture_u", "texture_s"]),
+        element.find(&["v", "t", "texture_v", "texture_t"]),
+    ) {
+        (Some(u), Some(v)) => Some([u, v]),
+        _ => None,
+    };
+    let color = match (
+        element.find(&["red", "r"]),
+        element.find(&["green", "g"]),
+        element.find(&["blue", "b"]),
+    ) {
+        (Some(r), Some(g), Some(b)) => Some([r, g, b]),
+        _ => None,
+    };
+    // Integer colors are 0-255, floating point colors are already 0-1.
+    let color_scale = match color.map(|color| &element.properties[color[0]].kind) {
+        Some(&PropertyKind::Scalar(Scalar::F32)) | Some(&PropertyKind::Scalar(Scalar::F64)) => 1.0,
+        Some(&PropertyKind::Scalar(Scalar::U16)) => 1.0 / 65535.0,
+        _ => 1.0 / 255.0,
+    };
+
+    let mut row = vec![0.0; element.properties.len()];
+    let mut values = Vec::new();
+    for _ in 0..element.count {
+        reader.start_element()?;
+        for (i, property) in element.properties.iter().enumerate() {
+            reader.read_property(&property.kind, &mut values)?;
+            row[i] = values.first().cloned().unwrap_or(0.0);
+        }
+        let vec3 = |indices: [usize; 3], scale: f64| {
+            Vec3(
+                (row[indices[0]] * scale) as f32,
+                (row[indices[1]] * scale) as f32,
+                (row[indices[2]] * scale) as f32,
+            )
+        };
+        mesh.positions.push(vec3(position, 1.0));
+        if let Some(normal) = normal {
+            mesh.normals.push(vec3(normal, 1.0));
+        }
+        if let Some(uv) = uv {
+            mesh.uvs.push((row[uv[0]] as f32, row[uv[1]] as f32));
+        }
+        if let Some(color) = color {
+            mesh.colors.push(vec3(color, color_scale));
+        }
+    }
+    Ok(())
+}
+
+fn read_faces(reader: &mut Reader, element: &Element, mesh: &mut Mesh) -> Result<(), String> {
+    let indices = element
+        .find(&["vertex_indices", "vertex_index"])
+        .ok_or("face has no vertex indices")?;
+    let mut values = Vec::new();
+    let mut face = Vec::new();
+    for _ in 0..element.count {
+        reader.start_element()?;
+        for (i, property) in element.properties.iter().enumerate() {
+            reader.read_property(&property.kind, &mut values)?;
+            if i == indices {
+                face.clear();
+                face.extend(values.iter().map(|&index| index as u32));
+            }
+        }
+        if face.len() < 3 {
+            return Err(format!(
+                "face needs at least 3 vertices, found {}",
+                face.len()
+            ));
+        }
+        for i in 1..face.len() - 1 {
+            mesh.indices.push([face[0], face[i], face[i + 1]]);
+        }
+    }
+    Ok(())
+}
+
+fn skip_element(reader: &mut Reader, element: &Element) -> Result<(), String> {
+    let mut values = Vec::new();
+    for _ in 0..element.count {
+        reader.start_element()?;
+        for property in &element.properties {
+            reader.read_property(&property.kind, &mut values)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32;
+    use std::sync::Arc;
+    use hitable::{triangles, Hitable};
+    use ray::Ray;
+    use texture::Texture;
+    use utils::*;
+
+    const POSITIONS: [[f32; 3]; 4] = [
+        [0.0, 0.0, 0.0],
+        [1.0, 0.0, 0.0],
+        [1.0, 1.0, 0.0],
+        [0.0, 1.0, 0.0],
+    ];
+    const COLORS: [[u8; 3]; 4] = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]];
+
+    fn header(format: &str) -> String {
+        format!(
+            "ply\nformat {} 1.0\ncomment a colored square\nelement vertex 4\n\
+             property float x\nproperty float y\nproperty float z\n\
+             property uchar red\nproperty uchar green\nproperty uchar blue\n\
+             element face 1\nproperty list uchar int vertex_indices\n\
+             element edge 1\nproperty int vertex1\nproperty int vertex2\nend_header\n",
+            format
+        )
+    }
+
+    fn ascii_square() -> Vec<u8> {
+        let mut text = header("ascii");
+        for (p, c) in POSITIONS.iter().zip(COLORS.iter()) {
+            text += &format!("{} {} {} {} {} {}\n", p[0], p[1], p[2], c[0], c[1], c[2]);
+        }
+        text += "4 0 1 2 3\n0 2\n";
+        text.into_bytes()
+    }
+
+    fn binary_square() -> Vec<u8> {
+        let mut data = header("binary_little_endian").into_bytes();
+        for (p, c) in POSITIONS.iter().zip(COLORS.iter()) {
+            for &x in p {
+                data.extend_from_slice(&x.to_le_bytes());
+            }
+            data.extend_from_slice(c);
+        }
+        data.push(4);
+        for &index in &[0i32, 1, 2, 3] {
+            data.extend_from_slice(&index.to_le_bytes());
+        }
+        for &index in &[0i32, 2] {
+            data.extend_from_slice(&index.to_le_bytes());
+        }
+        data
+    }
+
+    fn parse(data: &[u8]) -> Result<Mesh, LoadError> {
+        let material = new_labertian(Texture::VertexColor);
+        parse_ply(data, Path::new("test.ply"), material)
+    }
+
+    fn tuples(vectors: &[Vec3]) -> Vec<(f32, f32, f32)> {
+        vectors.iter().map(|v| (v.x(), v.y(), v.z())).collect()
+    }
+
+    fn check_square(mesh: &Mesh) {
+        let positions: Vec<(f32, f32, f32)> =
+            POSITIONS.iter().map(|p| (p[0], p[1], p[2])).collect();
+        assert_eq!(tuples(&mesh.positions), positions);
+        assert_eq!(
+            tuples(&mesh.colors),
+            vec![(1.0, 0.0, 0.0), (0.0, 1.0, 0.0), (0.0, 0.0, 1.0), (1.0, 1.0, 1.0)]
+        );
+        assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3]]);
+        assert!(mesh.normals.is_empty());
+        assert!(mesh.uvs.is_empty());
+    }
+
+    #[test]
+    fn reads_ascii() {
+        check_square(&parse(&ascii_square()).unwrap());
+    }
+
+    #[test]
+    fn reads_binary_little_endian() {
+        check_square(&parse(&binary_square()).unwrap());
+    }
+
+    #[test]
+    fn truncated_binary_is_an_error() {
+        let data = binary_square();
+        for &cut in &[1, 10, 30] {
+            let err = parse(&data[..data.len() - cut]).unwrap_err();
+            assert!(
+                err.to_string().starts_with("test.ply: unexpected end of file at byte"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn ascii_errors_report_the_line() {
+        let text = String::from_utf8(ascii_square()).unwrap();
+        let err = parse(text.replace("4 0 1 2 3", "4 0 1 x 3").as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "test.ply:21: expected a number, found `x`");
+        let err = parse(text.replace("4 0 1 2 3", "4 0 1 2 9").as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "test.ply: face refers to missing vertex 9");
+    }
+
+    #[test]
+    fn vertex_colors_are_interpolated_across_faces() {
+        let mesh = Arc::new(parse(&ascii_square()).unwrap());
+        let faces = triangles(&mesh);
+        let ray = Ray::new(Vec3(0.75, 0.25, -1.0), Vec3(0.0, 0.0, 1.0), 0.0);
+        let rec = faces
+            .iter()
+            .filter_map(|face: &Hitable| face.hit(&ray, 0.001, 10.0))
+            .next()
+            .unwrap();
+        // Barycentric weights 0.25, 0.5 and 0.25 of red, green and blue.
+        let color = Texture::VertexColor.value_at(&rec);
+        assert!((color - Vec3(0.25, 0.5, 0.25)).length() < 1e-5);
+        // The default material of a colored mesh picks them up as albedo.
+        let reflected = rec.material.eval(&ray, &rec, &rec.normal) * f32::consts::PI;
+        assert!((reflected - color).length() < 1e-5);
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use hitable::Mesh;
+use loader::LoadError;
+use material::Material;
+use vector::Vec3;
+
+const HEADER_SIZE: usize = 84;
+const TRIANGLE_SIZE: usize = 50;
+
+// Loads a binary STL file. Facets only store their corners, so identical
+// corners are merged to let neighbouring triangles share vertices.
+pub fn load_stl(path: &Path, material: Material) -> Result<Mesh, LoadError> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(|err| LoadError::new(path, 0, err.to_string()))?;
+    parse_stl(&data, path, material)
+}
+
+// The contents of the STL file at path, which errors are reported for.
+fn parse_stl(data: &[u8], path: &Path, material: Material) -> Result<Mesh, LoadError> {
+    if data.len() < HEADER_SIZE {
+        return Err(LoadError::new(
+            path,
+            0,
+            "file is too short for a binary stl".to_string(),
+        ));
+    }
+    let count = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+    let expected = HEADER_SIZE + count * TRIANGLE_SIZE;
+    if data.len() != expected {
+        let message = if data.starts_with(b"solid") {
+            "ascii stl files are not supported, convert the file to binary stl".to_string()
+        } else {
+            format!(
+                "header promises {} triangles ({} bytes) but the file has {} bytes",
+                count,
+                expected,
+                data.len()
+            )
+        };
+        return Err(LoadError::new(path, 0, message));
+    }
+
+    let mut mesh = Mesh::new(Vec::new(), Vec::with_capacity(count), material);
+    let mut vertex_ids: HashMap<[u32; 3], u32> = HashMap::new();
+    for triangle in data[HEADER_SIZE..].chunks(TRIANGLE_SIZE) {
+        let mut face = [0u32; 3];
+        // The 12 bytes of facet normal come first and are recomputed from
+        // the winding instead.
+        for (corner, id) in face.iter_mut().enumerate() {
+            let start = 12 + corner * 12;
+            let mut bits = [0u32; 3];
+            for (axis, bit) in bits.iter_mut().enumerate() {
+                let offset = start + axis * 4;
+                *bit = u32::from_le_bytes([
+                    triangle[offset],
+                    triangle[offset + 1],
+                    triangle[offset + 2],
+                    triangle[offset + 3],
+                ]);
+            }
+            let positions = &mut mesh.positions;
+            *id = *vertex_ids.entry(bits).or_insert_with(|| {
+                positions.push(Vec3(
+                    f32::from_bits(bits[0]),
+                    f32::from_bits(bits[1]),
+                    f32::from_bits(bits[2]),
+                ));
+                positions.len() as u32 - 1
+            });
+        }
+        mesh.indices.push(face);
+    }
+    mesh.validate()
+        .map_err(|message| LoadError::new(path, 0, message))?;
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use texture::Texture;
+    use utils::*;
+
+    fn binary_stl(facets: &[[[f32; 3]; 3]]) -> Vec<u8> {
+        let mut data = vec![0u8; 80];
+        data.extend_from_slice(&(facets.len() as u32).to_le_bytes());
+        for facet in facets {
+            // The normal is ignored, so any will do.
+            data.extend_from_slice(&[0u8; 12]);
+            for corner in facet {
+                for &x in corner {
+                    data.extend_from_slice(&x.to_le_bytes());
+                }
+            }
+            data.extend_from_slice(&[0u8; 2]);
+        }
+        data
+    }
+
+    fn parse(data: &[u8]) -> Result<Mesh, LoadError> {
+        let material = new_labertian(Texture::VertexColor);
+        parse_stl(data, Path::new("test.stl"), material)
+    }
+
+    fn square() -> Vec<u8> {
+        binary_stl(&[
+            [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]],
+            [[0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]],
+        ])
+    }
+
+    #[test]
+    fn shares_identical_corners() {
+        let mesh = parse(&square()).unwrap();
+        let positions: Vec<(f32, f32, f32)> =
+            mesh.positions.iter().map(|p| (p.x(), p.y(), p.z())).collect();
+        assert_eq!(
+            positions,
+            vec![(0.0, 0.0, 0.0), (1.0, 0.0, 0.0), (1.0, 1.0, 0.0), (0.0, 1.0, 0.0)]
+        );
+        assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3]]);
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let data = square();
+        let err = parse(&data[..data.len() - 1]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test.stl: header promises 2 triangles (184 bytes) but the file has 183 bytes"
+        );
+        let err = parse(&data[..40]).unwrap_err();
+        assert_eq!(err.to_string(), "test.stl: file is too short for a binary stl");
+    }
+
+    #[test]
+    fn ascii_stl_is_an_error() {
+        let text = "solid square\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\n\
+                    vertex 1 1 0\nendloop\nendfacet\nendsolid square\n";
+        let err = parse(text.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test.stl: ascii stl files are not supported, convert the file to binary stl"
+        );
+    }
+
+    #[test]
+    fn empty_file_is_an_error() {
+        let err = parse(&binary_stl(&[])).unwrap_err();
+        assert_eq!(err.to_string(), "test.stl: mesh has no faces");
+    }
+}
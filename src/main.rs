@@ -1,13 +1,11 @@
 extern crate rand;
 extern crate rayon;
 extern crate stb_image;
+extern crate png;
 
-use std::fs::File;
-use std::io::Write;
-use std::f32;
+use std::env;
+use std::process;
 use std::time::{Duration, Instant};
-use rand::{thread_rng, Rng};
-use rayon::prelude::*;
 
 mod vector;
 mod ray;
@@ -18,115 +16,228 @@ mod material;
 mod utils;
 mod texture;
 mod perlin;
+mod scene;
+mod cli;
+mod output;
+mod loader;
+mod bench;
+mod light;
+mod integrator;
+mod sampler;
+mod render;
+mod checkpoint;
+mod filter;
+mod microfacet;
 
-use vector::*;
-use ray::*;
 use hitable::*;
 use camera::*;
-use material::*;
 use utils::*;
+use scene::*;
+use cli::*;
+use output::*;
+use bench::*;
+use light::*;
+use render::*;
+use checkpoint::*;
 
-fn color(r: &Ray, world: &Hitable, depth: u32) -> Vec3 {
-    match world.hit(r, 0.001, f32::MAX) {
-        Some(rec) => {
-            let emitted = rec.material.emitted(rec.u, rec.v, &rec.p);
-            if depth < 50 {
-                match rec.material.scatter(r, &rec) {
-                    Scattered::Yes((scattered, attenuation)) => {
-                        return emitted + attenuation * color(&scattered, world, depth + 1)
-                    }
-                    _ => return emitted,
-                }
-            }
-            emitted
+fn main() {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, usage());
+            process::exit(2);
         }
-        None => Vec3(0.0, 0.0, 0.0),
+    };
+    if options.help {
+        print!("{}", usage());
+        return;
+    }
+    if options.threads > 0 {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(options.threads)
+            .build_global()
+            .expect("Couldn't configure render threads");
     }
-}
 
-fn main() {
+    let writer = match ImageWriter::for_path(&options.output, options.display) {
+        Ok(writer) => writer,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(2);
+        }
+    };
+
     let now = Instant::now();
-    const NX: u32 = 600;
-    const NY: u32 = 300;
-    let ns = 500;
+    let nx = options.width;
+    let ny = options.height;
+    let ns = options.samples;
+    let max_depth = options.max_depth;
+    let roulette = options.roulette;
+    let seed = options.seed;
     println!("Making picture");
-    let mut f = File::create("/home/tokuogum/Rust/rust-ray-tracer/picture.ppm")
-        .expect("Couldn't create picture file");
-    write!(&mut f, "P3\n{} {}\n255\n", NX, NY).unwrap();
-
-    // let mut world_list = random_scene();
-    // let lookfrom = Vec3(13.0, 2.0, 3.0);
-    // let lookat = Vec3(0.0, 0.0, 0.0);
-    // //   let dist_to_focus = (lookfrom - lookat).length();
-    // let dist_to_focus = 10.0;
-    // let aperture = 0.0;
-    // let camera = Camera::new(
-    //     lookfrom,
-    //     lookat,
-    //     Vec3(0.0, 1.0, 0.0),
-    //     20.0,
-    //     nx as f32 / ny as f32,
-    //     aperture,
-    //     dist_to_focus,
-    //     0.0,
-    //     1.0,
-    // );
-
-    // let mut world_list = simple_light();
-    // //    let lookfrom = Vec3(13.0, 2.0, 3.0);
-    // let lookfrom = Vec3(25.0, 2.0, 3.0);
-    // let lookat = Vec3(0.0, 0.0, 0.0);
-    // let dist_to_focus = 10.0;
-    // let aperture = 0.0;
-    // let camera = Camera::new(
-    //     lookfrom,
-    //     lookat,
-    //     Vec3(0.0, 1.0, 0.0),
-    //     20.0,
-    //     NX as f32 / NY as f32,
-    //     aperture,
-    //     dist_to_focus,
-    //     0.0,
-    //     1.0,
-    // );
-
-    let (camera, mut world_list) = cornell_box(NX, NY);
 
-    println!("Starting making bvh at {}", now.elapsed().as_secs());
-    let bbox = Hitable::BvhNode(BvhNode::new(&mut world_list, 0.0, 1.0));
-    println!("Finishing making bvh at {}", now.elapsed().as_secs());
-    let mut picture_array: Vec<Vec<(u32, u32, u32)>> = Vec::new();
-    for j in (0..NY).rev() {
-        picture_array.push(
-            (0..NX)
-                .into_par_iter()
-                .map(|i| {
-                    let mut col: Vec3 = (0..ns)
-                        .into_par_iter()
-                        .map(|_| {
-                            let mut rng = thread_rng();
-                            let u = (i as f32 + rng.gen::<f32>()) / NX as f32;
-                            let v = (j as f32 + rng.gen::<f32>()) / NY as f32;
-                            let ray = camera.get_ray(u, v);
-                            color(&ray, &bbox, 0)
-                        })
-                        .reduce_with(|sum, val| sum + val)
-                        .unwrap();
-                    col = col / (ns as f32);
-                    col = Vec3(col.0.sqrt(), col.1.sqrt(), col.2.sqrt());
-                    let ir = (255.99 * col.r()) as u32;
-                    let ig = (255.99 * col.g()) as u32;
-                    let ib = (255.99 * col.b()) as u32;
-                    (ir, ig, ib)
-                })
-                .collect(),
+    let (camera, world_list) = match options.scene {
+        SceneSource::Builtin(ref name) => match builtin_scene(name, nx, ny, seed) {
+            Ok(scene) => scene,
+            Err(err) => {
+                eprintln!("error: {}", err);
+                process::exit(1);
+            }
+        },
+        SceneSource::File(ref path) => match load_scene(path, nx, ny, seed) {
+            Ok(scene) => scene,
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        },
+    };
+    if options.bench_bvh {
+        bench_bvh(&camera, &world_list, nx, ny, ns, seed);
+        return;
+    }
+    let build_start = Instant::now();
+    let world = options.bvh.build(world_list, 0.0, 1.0);
+    let build_time = build_start.elapsed();
+    let stats = BvhStats::of(&world, 0.0, 1.0);
+    println!(
+        "Built {} bvh over {} primitives in {:.3}s: {} nodes, {} leaves, depth {}, SAH cost {:.2}",
+        options.bvh.name(),
+        stats.primitives,
+        build_time.as_secs_f64(),
+        stats.nodes,
+        stats.leaves,
+        stats.max_depth,
+        stats.sah_cost
+    );
+    let lights = LightList::new(&world);
+    let integrator = options.integrator;
+    println!(
+        "Rendering with the {} integrator, {} sampled lights",
+        integrator.name(),
+        lights.len()
+    );
+    let settings = RenderSettings {
+        width: nx,
+        height: ny,
+        samples: ns,
+        max_depth,
+        roulette,
+        integrator,
+        sampler: options.sampler,
+        seed,
+        region: options.crop.unwrap_or_else(|| Region::full(nx, ny)),
+        tile_size: options.tile_size,
+        tile_order: options.tile_order,
+        adaptive: options.adaptive,
+        filter: options.filter,
+        indirect_clamp: options.indirect_clamp,
+        debug_invalid: options.debug_invalid,
+    };
+    if options.bench_samplers {
+        bench_samplers(
+            |sampler, samples, seed| {
+                let settings = RenderSettings {
+                    sampler,
+                    samples,
+                    seed,
+                    ..settings
+                };
+                Renderer::new(&camera, &world, &lights, settings)
+                    .render()
+                    .to_framebuffer()
+            },
+            ns,
+            seed,
         );
+        return;
     }
-    for j in (0..NY) {
-        for i in 0..NX {
-            let (ir, ig, ib) = picture_array[j as usize][i as usize];
-            write!(&mut f, "{} {} {}\n", ir, ig, ib).unwrap();
+    println!(
+        "Sampling with the {} sampler and a {} filter of radius {}, in {}x{} tiles, {} order",
+        options.sampler.name(),
+        options.filter.kind.name(),
+        options.filter.radius,
+        options.tile_size,
+        options.tile_size,
+        options.tile_order.name()
+    );
+    if let Some(crop) = options.crop {
+        println!(
+            "Cropping to {}x{} pixels at {},{}",
+            crop.width, crop.height, crop.x, crop.y
+        );
+    }
+    let renderer = Renderer::new(&camera, &world, &lights, settings);
+    let scene = match options.scene {
+        SceneSource::Builtin(ref name) => format!("scene {}", name),
+        SceneSource::File(ref path) => format!("scene file {}", path.display()),
+    };
+    let description = format!("{} {}", settings.describe(), scene);
+    let film = if options.progressive.pass_samples > 0 {
+        let film = match options.checkpoint {
+            Some(ref path) if options.resume => {
+                match load_checkpoint(path, &description, &settings.region) {
+                    Ok(film) => {
+                        println!(
+                            "Resuming from {} at {:.1} spp",
+                            path.display(),
+                            film.mean_samples()
+                        );
+                        film
+                    }
+                    Err(err) => {
+                        eprintln!("Couldn't resume: {}", err);
+                        process::exit(1);
+                    }
+                }
+            }
+            _ => Film::new(&settings.region),
+        };
+        options.progressive.render(&renderer, film, |film| {
+            match writer.save(&film.to_framebuffer(), &options.output) {
+                Ok(()) => println!(
+                    "Wrote snapshot at {:.1} spp to {}",
+                    film.mean_samples(),
+                    options.output.display()
+                ),
+                Err(err) => eprintln!("Couldn't write {}: {}", options.output.display(), err),
+            }
+            if let Some(ref path) = options.checkpoint {
+                match save_checkpoint(path, &description, film) {
+                    Ok(()) => println!("Wrote checkpoint to {}", path.display()),
+                    Err(err) => eprintln!("Couldn't write {}: {}", path.display(), err),
+                }
+            }
+        })
+    } else {
+        renderer.render()
+    };
+    let (nan_samples, infinite_samples) = renderer.invalid_samples();
+    if nan_samples + infinite_samples > 0 {
+        println!(
+            "Replaced {} NaN and {} infinite samples with black",
+            nan_samples, infinite_samples
+        );
+    }
+    if let Some(adaptive) = options.adaptive {
+        println!(
+            "Adaptive sampling spent {:.1} spp on average",
+            film.mean_samples()
+        );
+        if let Some(ref path) = options.heatmap {
+            let heatmap = film.heatmap(adaptive.max_samples);
+            let saved = ImageWriter::for_path(path, DisplayTransform::new())
+                .and_then(|writer| writer.save(&heatmap, path).map_err(|err| err.to_string()));
+            if let Err(err) = saved {
+                eprintln!("Couldn't write {}: {}", path.display(), err);
+                process::exit(1);
+            }
         }
     }
+    let framebuffer = film.to_framebuffer();
+    if let Err(err) = writer.save(&framebuffer, &options.output) {
+        eprintln!("Couldn't write {}: {}", options.output.display(), err);
+        process::exit(1);
+    }
     println!("Elapsed time {}", now.elapsed().as_secs());
 }
@@ -1,42 +1,126 @@
-use rand::{thread_rng, Rng};
+use std::f32;
 
 use ray::*;
 use hitable::*;
 use vector::*;
 use utils::*;
 use texture::*;
-
-pub enum Scattered<T> {
-    Yes(T),
-    No,
-}
+use sampler::Sampler;
+use microfacet::*;
 
 #[derive(Clone, Debug)]
 pub enum Material {
     Labertian(Labertian),
     Metal(Metal),
+    Conductor(Conductor),
     Dielectric(Dielectric),
     DiffuceLight(DiffuceLight),
     Isotropic(Isotropic),
+    Principled(Principled),
+    Mapped(Mapped),
+}
+
+// A scattered direction with its throughput weight, f * cos / pdf. Delta
+// lobes like mirrors and glass have no meaningful pdf and leave it at zero.
+pub struct BsdfSample {
+    pub direction: Vec3,
+    pub weight: Vec3,
+    pub pdf: f32,
+    pub delta: bool,
 }
 
 impl Material {
-    pub fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Scattered<(Ray, Vec3)> {
+    pub fn sample(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut Sampler,
+    ) -> Option<BsdfSample> {
         match *self {
-            Material::Labertian(ref labertian) => labertian.scatter(r_in, rec),
-            Material::Metal(ref metal) => metal.scatter(r_in, rec),
-            Material::Dielectric(ref dielectric) => dielectric.scatter(r_in, rec),
-            Material::DiffuceLight(ref diffuce) => diffuce.scatter(r_in, rec),
-            Material::Isotropic(ref isotropic) => isotropic.scatter(r_in, rec),
+            Material::Labertian(ref labertian) => labertian.sample(rec, sampler),
+            Material::Metal(ref metal) => metal.sample(r_in, rec, sampler),
+            Material::Conductor(ref conductor) => conductor.sample(r_in, rec, sampler),
+            Material::Dielectric(ref dielectric) => dielectric.sample(r_in, rec, sampler),
+            Material::DiffuceLight(_) => None,
+            Material::Isotropic(ref isotropic) => isotropic.sample(rec, sampler),
+            Material::Principled(ref principled) => principled.sample(r_in, rec, sampler),
+            Material::Mapped(ref mapped) => {
+                mapped.material.sample(r_in, &mapped.shade(rec), sampler)
+            }
+        }
+    }
+
+    // Scattered radiance towards the viewer per unit of radiance arriving
+    // from the unit vector direction, including the cosine term. Delta lobes
+    // contribute nothing to any given direction.
+    pub fn eval(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        match *self {
+            Material::Labertian(ref labertian) => labertian.eval(rec, direction),
+            Material::Metal(ref metal) => metal.eval(r_in, rec, direction),
+            Material::Conductor(ref conductor) => conductor.eval(r_in, rec, direction),
+            Material::Dielectric(ref dielectric) => dielectric.eval(r_in, rec, direction),
+            Material::Isotropic(ref isotropic) => isotropic.eval(rec),
+            Material::Principled(ref principled) => principled.eval(r_in, rec, direction),
+            Material::Mapped(ref mapped) => {
+                mapped.material.eval(r_in, &mapped.shade(rec), direction)
+            }
+            _ => Vec3(0.0, 0.0, 0.0),
+        }
+    }
+
+    // Solid angle density of sample picking direction.
+    pub fn pdf(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> f32 {
+        match *self {
+            Material::Labertian(ref labertian) => labertian.pdf(rec, direction),
+            Material::Metal(ref metal) => metal.pdf(r_in, rec, direction),
+            Material::Conductor(ref conductor) => conductor.pdf(r_in, rec, direction),
+            Material::Dielectric(ref dielectric) => dielectric.pdf(r_in, rec, direction),
+            Material::Isotropic(ref isotropic) => isotropic.pdf(),
+            Material::Principled(ref principled) => principled.pdf(r_in, rec, direction),
+            Material::Mapped(ref mapped) => {
+                mapped.material.pdf(r_in, &mapped.shade(rec), direction)
+            }
+            _ => 0.0,
         }
     }
 
     pub fn emitted(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
         match *self {
             Material::DiffuceLight(ref diffuce) => diffuce.emitted(u, v, p),
+            Material::Mapped(ref mapped) => mapped.material.emitted(u, v, p),
             _ => Vec3(0.0, 0.0, 0.0),
         }
     }
+
+    // Share of light that makes it along the ray that found rec, less than
+    // one after travelling through the inside of an absorbing material.
+    pub fn transmittance(&self, r_in: &Ray, rec: &HitRecord) -> Vec3 {
+        match *self {
+            Material::Dielectric(ref dielectric) => dielectric.transmittance(r_in, rec),
+            Material::Mapped(ref mapped) => mapped.material.transmittance(r_in, rec),
+            _ => Vec3(1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn is_light(&self) -> bool {
+        match *self {
+            Material::DiffuceLight(_) => true,
+            Material::Mapped(ref mapped) => mapped.material.is_light(),
+            _ => false,
+        }
+    }
+
+    // Materials that only have delta lobes never scatter into a direction a
+    // light sample picks, so they get no direct lighting.
+    pub fn is_specular(&self) -> bool {
+        match *self {
+            Material::Metal(ref metal) => metal.fuzz == 0.0,
+            Material::Conductor(ref conductor) => conductor.distribution.is_smooth(),
+            Material::Dielectric(ref dielectric) => dielectric.distribution.is_smooth(),
+            Material::Mapped(ref mapped) => mapped.material.is_specular(),
+            _ => false,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -49,11 +133,21 @@ impl Isotropic {
         Isotropic { albedo: a }
     }
 
-    pub fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Scattered<(Ray, Vec3)> {
-        Scattered::Yes((
-            Ray::new(rec.p, random_in_unit_sphere(), r_in.time()),
-            self.albedo.value(rec.u, rec.v, &rec.p),
-        ))
+    pub fn sample(&self, rec: &HitRecord, sampler: &mut Sampler) -> Option<BsdfSample> {
+        Some(BsdfSample {
+            direction: sample_unit_vector(sampler.next_2d()),
+            weight: self.albedo.value_at(rec),
+            pdf: self.pdf(),
+            delta: false,
+        })
+    }
+
+    pub fn eval(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value_at(rec) * self.pdf()
+    }
+
+    pub fn pdf(&self) -> f32 {
+        1.0 / (4.0 * f32::consts::PI)
     }
 }
 
@@ -67,10 +161,6 @@ impl DiffuceLight {
         DiffuceLight { emit: a }
     }
 
-    pub fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Scattered<(Ray, Vec3)> {
-        Scattered::No
-    }
-
     pub fn emitted(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
         self.emit.value(u, v, p)
     }
@@ -96,22 +186,411 @@ impl Metal {
         }
     }
 
-    pub fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Scattered<(Ray, Vec3)> {
+    // The mirror direction pushed to a uniform point in a ball of radius
+    // fuzz around it. Directions that end up below the surface are absorbed.
+    pub fn sample(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut Sampler) -> Option<BsdfSample> {
         let reflected = reflect(&unit_vector(r_in.direction()), &rec.normal);
-        let scattered = Ray::new(
-            rec.p,
-            reflected + random_in_unit_sphere() * self.fuzz,
-            r_in.time(),
-        );
-        let attenuation = self.albedo;
-        if dot(&scattered.direction(), &rec.normal) > 0.0 {
-            Scattered::Yes((scattered, attenuation))
-        } else {
-            Scattered::No
+        let ball = sample_unit_ball(sampler.next_2d(), sampler.next_1d());
+        let direction = unit_vector(reflected + ball * self.fuzz);
+        if dot(&direction, &rec.normal) <= 0.0 {
+            return None;
+        }
+        Some(BsdfSample {
+            direction,
+            weight: self.albedo,
+            pdf: self.pdf(r_in, rec, &direction),
+            delta: self.fuzz == 0.0,
+        })
+    }
+
+    pub fn eval(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        self.albedo * self.pdf(r_in, rec, direction)
+    }
+
+    // Share of the fuzz ball seen along direction, which is the integral of
+    // t^2 over the chord through the ball divided by its volume.
+    pub fn pdf(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> f32 {
+        if self.fuzz == 0.0 || dot(direction, &rec.normal) <= 0.0 {
+            return 0.0;
+        }
+        let reflected = reflect(&unit_vector(r_in.direction()), &rec.normal);
+        let b = dot(direction, &reflected);
+        let discriminant = b * b - reflected.squared_length() + self.fuzz * self.fuzz;
+        if discriminant <= 0.0 {
+            return 0.0;
+        }
+        let root = discriminant.sqrt();
+        let far = b + root;
+        let near = (b - root).max(0.0);
+        if far <= 0.0 {
+            return 0.0;
+        }
+        (far.powi(3) - near.powi(3)) / (4.0 * f32::consts::PI * self.fuzz.powi(3))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetalPreset {
+    Gold,
+    Copper,
+    Silver,
+    Aluminium,
+}
+
+impl MetalPreset {
+    pub fn from_name(name: &str) -> Option<MetalPreset> {
+        match name {
+            "gold" => Some(MetalPreset::Gold),
+            "copper" => Some(MetalPreset::Copper),
+            "silver" => Some(MetalPreset::Silver),
+            "aluminium" => Some(MetalPreset::Aluminium),
+            _ => None,
+        }
+    }
+
+    // Measured index of refraction eta + ik at the red, green and blue
+    // wavelengths of 650, 550 and 450 nm.
+    pub fn eta_k(&self) -> (Vec3, Vec3) {
+        match *self {
+            MetalPreset::Gold => (Vec3(0.143, 0.374, 1.442), Vec3(3.983, 2.385, 1.603)),
+            MetalPreset::Copper => (Vec3(0.200, 0.924, 1.102), Vec3(3.912, 2.452, 2.142)),
+            MetalPreset::Silver => (Vec3(0.155, 0.117, 0.138), Vec3(4.828, 3.122, 2.147)),
+            MetalPreset::Aluminium => (Vec3(1.657, 0.880, 0.521), Vec3(9.224, 6.270, 4.837)),
+        }
+    }
+}
+
+// A metal with GGX microfacets, reflecting by the Fresnel equations for
+// its complex index of refraction. Roughness can differ along the u and v
+// directions of the surface for brushed looks, and near zero it's a mirror.
+// Both sides of a surface reflect alike.
+#[derive(Clone, Debug)]
+pub struct Conductor {
+    eta: Vec3,
+    k: Vec3,
+    distribution: Ggx,
+}
+
+impl Conductor {
+    pub fn new(eta: Vec3, k: Vec3, roughness_x: f32, roughness_y: f32) -> Conductor {
+        Conductor {
+            eta,
+            k,
+            distribution: Ggx::new(roughness_x, roughness_y),
+        }
+    }
+
+    // Picks a visible microfacet normal and reflects about it, the weight
+    // is then Fresnel times the share of the light that isn't shadowed.
+    pub fn sample(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut Sampler) -> Option<BsdfSample> {
+        let (frame, wo) = shading_frame(r_in, rec);
+        if self.distribution.is_smooth() {
+            let wi = Vec3(-wo.x(), -wo.y(), wo.z());
+            return Some(BsdfSample {
+                direction: frame.to_world(&wi),
+                weight: fresnel_conductor(wo.z(), &self.eta, &self.k),
+                pdf: 0.0,
+                delta: true,
+            });
+        }
+        let m = self.distribution.sample_visible(&wo, sampler.next_2d());
+        let wi = reflect_about(&wo, &m);
+        if wi.z() <= 0.0 {
+            return None;
+        }
+        let cos_m = dot(&wo, &m);
+        let fresnel = fresnel_conductor(cos_m, &self.eta, &self.k);
+        Some(BsdfSample {
+            direction: frame.to_world(&wi),
+            weight: fresnel * (self.distribution.g(&wo, &wi) / self.distribution.g1(&wo)),
+            pdf: self.distribution.visible_pdf(&wo, &m) / (4.0 * cos_m),
+            delta: false,
+        })
+    }
+
+    pub fn eval(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        let (frame, wo) = shading_frame(r_in, rec);
+        let wi = frame.to_local(direction);
+        if self.distribution.is_smooth() || wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return Vec3(0.0, 0.0, 0.0);
+        }
+        let m = unit_vector(wo + wi);
+        let fresnel = fresnel_conductor(dot(&wo, &m), &self.eta, &self.k);
+        fresnel
+            * (self.distribution.d(&m) * self.distribution.g(&wo, &wi) / (4.0 * wo.z()))
+    }
+
+    pub fn pdf(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> f32 {
+        let (frame, wo) = shading_frame(r_in, rec);
+        let wi = frame.to_local(direction);
+        if self.distribution.is_smooth() || wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return 0.0;
+        }
+        let m = unit_vector(wo + wi);
+        self.distribution.visible_pdf(&wo, &m) / (4.0 * dot(&wo, &m))
+    }
+}
+
+// One material for most surfaces, after Burley's "Physically Based Shading
+// at Disney". Every parameter is a texture, the scalar ones use the mean of
+// its channels and go from 0 to 1.
+//
+// base_color: diffuse color, the reflectance of metals and the tint of
+//   transmitted light.
+// metallic: blends from a dielectric to a metal reflecting base_color.
+// roughness: of the GGX specular and transmission lobes.
+// specular: the dielectric's reflectance, 0.5 is 4% at normal incidence
+//   like glass with an index of refraction of 1.5.
+// sheen: extra grazing reflection for cloth.
+// clearcoat: a second, glossy specular layer like varnish.
+// transmission: blends the diffuse base into rough glass.
+//
+// A sample picks one of the lobes, but the weight and pdf come from all of
+// them so any lobe could have made it.
+#[derive(Clone, Debug)]
+pub struct Principled {
+    pub base_color: Texture,
+    pub metallic: Texture,
+    pub roughness: Texture,
+    pub specular: Texture,
+    pub sheen: Texture,
+    pub clearcoat: Texture,
+    pub transmission: Texture,
+}
+
+// Clearcoat always has this roughness.
+const CLEARCOAT_ROUGHNESS: f32 = 0.1;
+// Kept above the point where the specular lobes would turn into mirrors,
+// which one sample pdf for all lobes can't represent.
+const MIN_ROUGHNESS: f32 = 0.05;
+
+impl Principled {
+    pub fn new(base_color: Texture) -> Principled {
+        let constant = |value: f32| new_constant_texture(Vec3(value, value, value));
+        Principled {
+            base_color,
+            metallic: constant(0.0),
+            roughness: constant(0.5),
+            specular: constant(0.5),
+            sheen: constant(0.0),
+            clearcoat: constant(0.0),
+            transmission: constant(0.0),
+        }
+    }
+
+    fn lobes(&self, r_in: &Ray, rec: &HitRecord) -> PrincipledLobes {
+        let scalar = |texture: &Texture| {
+            let value = texture.value_at(rec);
+            ((value.x() + value.y() + value.z()) / 3.0).clamp(0.0, 1.0)
+        };
+        let metallic = scalar(&self.metallic);
+        let transmission = scalar(&self.transmission);
+        let roughness = scalar(&self.roughness).max(MIN_ROUGHNESS);
+        // The index of refraction whose reflectance at normal incidence is
+        // 8% of specular.
+        let r0 = (0.08 * scalar(&self.specular)).sqrt().min(0.99);
+        let ior = (1.0 + r0) / (1.0 - r0);
+        let (frame, wo) = shading_frame(r_in, rec);
+        PrincipledLobes {
+            frame,
+            wo,
+            base_color: self.base_color.value_at(rec),
+            metallic,
+            sheen: scalar(&self.sheen),
+            clearcoat: scalar(&self.clearcoat),
+            diffuse_weight: (1.0 - metallic) * (1.0 - transmission),
+            transmission_weight: (1.0 - metallic) * transmission,
+            eta: if dot(&r_in.direction(), &rec.normal) > 0.0 {
+                1.0 / ior
+            } else {
+                ior
+            },
+            specular: Ggx::new(roughness, roughness),
+            coat: Ggx::new(CLEARCOAT_ROUGHNESS, CLEARCOAT_ROUGHNESS),
+        }
+    }
+
+    pub fn sample(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut Sampler) -> Option<BsdfSample> {
+        let lobes = self.lobes(r_in, rec);
+        let wo = lobes.wo;
+        if wo.z() <= 0.0 {
+            return None;
+        }
+        let probabilities = lobes.probabilities();
+        let mut choice = sampler.next_1d();
+        let u = sampler.next_2d();
+        let mut lobe = 0;
+        while lobe < 3 && choice >= probabilities[lobe] {
+            choice -= probabilities[lobe];
+            lobe += 1;
+        }
+        let wi = match lobe {
+            0 => unit_vector(Vec3(0.0, 0.0, 1.0) + sample_unit_vector(u)),
+            1 => reflect_about(&wo, &lobes.specular.sample_visible(&wo, u)),
+            2 => reflect_about(&wo, &lobes.coat.sample_visible(&wo, u)),
+            _ => refract_about(&wo, &lobes.specular.sample_visible(&wo, u), lobes.eta)?,
+        };
+        let pdf = lobes.pdf(&wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(BsdfSample {
+            direction: lobes.frame.to_world(&wi),
+            weight: lobes.eval(&wi) * (1.0 / pdf),
+            pdf,
+            delta: false,
+        })
+    }
+
+    pub fn eval(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        let lobes = self.lobes(r_in, rec);
+        lobes.eval(&lobes.frame.to_local(direction))
+    }
+
+    pub fn pdf(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> f32 {
+        let lobes = self.lobes(r_in, rec);
+        lobes.pdf(&lobes.frame.to_local(direction))
+    }
+}
+
+// The principled material's parameters at one hit, with directions in the
+// frame around the normal on the viewer's side.
+struct PrincipledLobes {
+    frame: Frame,
+    wo: Vec3,
+    base_color: Vec3,
+    metallic: f32,
+    sheen: f32,
+    clearcoat: f32,
+    diffuse_weight: f32,
+    transmission_weight: f32,
+    eta: f32,
+    specular: Ggx,
+    coat: Ggx,
+}
+
+impl PrincipledLobes {
+    // Chances of sampling the diffuse, specular, clearcoat and transmission
+    // lobes, roughly in proportion to how much light they reflect towards
+    // the viewer.
+    fn probabilities(&self) -> [f32; 4] {
+        let cos_o = self.wo.z();
+        let fresnel = fresnel_dielectric(cos_o, self.eta);
+        let luminance = |color: Vec3| 0.2126 * color.r() + 0.7152 * color.g() + 0.0722 * color.b();
+        let weights = [
+            self.diffuse_weight * (luminance(self.base_color) + self.sheen),
+            (1.0 - self.metallic) * fresnel
+                + self.metallic * luminance(schlick(self.base_color, cos_o)),
+            0.25 * self.clearcoat * fresnel_dielectric(cos_o, 1.5),
+            self.transmission_weight * (1.0 - fresnel),
+        ];
+        let total: f32 = weights.iter().sum();
+        if total <= 0.0 {
+            return [0.0, 1.0, 0.0, 0.0];
+        }
+        [
+            weights[0] / total,
+            weights[1] / total,
+            weights[2] / total,
+            weights[3] / total,
+        ]
+    }
+
+    fn eval(&self, wi: &Vec3) -> Vec3 {
+        let wo = &self.wo;
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return Vec3(0.0, 0.0, 0.0);
+        }
+        if wi.z() < 0.0 {
+            let m = match refraction_half_vector(wo, wi, self.eta) {
+                Some(m) => m,
+                None => return Vec3(0.0, 0.0, 0.0),
+            };
+            let cos_o = dot(wo, &m);
+            let cos_i = dot(wi, &m);
+            let denominator = cos_o + self.eta * cos_i;
+            let value = self.transmission_weight
+                * (1.0 - fresnel_dielectric(cos_o, self.eta))
+                * self.specular.d(&m)
+                * self.specular.g(wo, wi)
+                * cos_o
+                * self.eta
+                * self.eta
+                * cos_i.abs()
+                / (wo.z() * denominator * denominator);
+            return self.base_color * value;
+        }
+        let m = unit_vector(*wo + *wi);
+        let cos_d = dot(wi, &m);
+        let sheen = self.sheen * (1.0 - cos_d).max(0.0).powi(5);
+        // The diffuse base only gets the light the specular layer lets
+        // through, on the way in and out.
+        let through = (1.0 - fresnel_dielectric(wo.z(), self.eta))
+            * (1.0 - fresnel_dielectric(wi.z(), self.eta));
+        let diffuse = (self.base_color * (through / f32::consts::PI) + Vec3(sheen, sheen, sheen))
+            * (self.diffuse_weight * wi.z());
+        let fresnel = schlick(self.base_color, cos_d) * self.metallic
+            + Vec3(1.0, 1.0, 1.0) * ((1.0 - self.metallic) * fresnel_dielectric(cos_d, self.eta));
+        let specular =
+            fresnel * (self.specular.d(&m) * self.specular.g(wo, wi) / (4.0 * wo.z()));
+        let coat = 0.25
+            * self.clearcoat
+            * fresnel_dielectric(cos_d, 1.5)
+            * self.coat.d(&m)
+            * self.coat.g(wo, wi)
+            / (4.0 * wo.z());
+        diffuse + specular + Vec3(coat, coat, coat)
+    }
+
+    fn pdf(&self, wi: &Vec3) -> f32 {
+        let wo = &self.wo;
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return 0.0;
         }
+        let probabilities = self.probabilities();
+        if wi.z() < 0.0 {
+            return match refraction_half_vector(wo, wi, self.eta) {
+                Some(m) => {
+                    let denominator = dot(wo, &m) + self.eta * dot(wi, &m);
+                    probabilities[3]
+                        * self.specular.visible_pdf(wo, &m)
+                        * self.eta
+                        * self.eta
+                        * dot(wi, &m).abs()
+                        / (denominator * denominator)
+                }
+                None => 0.0,
+            };
+        }
+        let m = unit_vector(*wo + *wi);
+        let cos_m = dot(wo, &m);
+        probabilities[0] * wi.z() / f32::consts::PI
+            + probabilities[1] * self.specular.visible_pdf(wo, &m) / (4.0 * cos_m)
+            + probabilities[2] * self.coat.visible_pdf(wo, &m) / (4.0 * cos_m)
     }
 }
 
+// Schlick's approximation of the Fresnel reflectance of a metal with
+// reflectance r0 at normal incidence.
+fn schlick(r0: Vec3, cos_theta: f32) -> Vec3 {
+    let weight = (1.0 - cos_theta).clamp(0.0, 1.0).powi(5);
+    r0 + (Vec3(1.0, 1.0, 1.0) - r0) * weight
+}
+
+// Frame around the normal turned towards the viewer with s along dpdu, and
+// the direction to the viewer in it.
+fn shading_frame(r_in: &Ray, rec: &HitRecord) -> (Frame, Vec3) {
+    let wo = unit_vector(r_in.direction()) * -1.0;
+    let normal = if dot(&wo, &rec.normal) < 0.0 {
+        rec.normal * -1.0
+    } else {
+        rec.normal
+    };
+    let frame = Frame::from_tangent(normal, &rec.dpdu);
+    let wo = frame.to_local(&wo);
+    (frame, wo)
+}
+
 #[derive(Clone, Debug)]
 pub struct Labertian {
     albedo: Texture,
@@ -122,78 +601,267 @@ impl Labertian {
         Labertian { albedo: albedo }
     }
 
-    pub fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Scattered<(Ray, Vec3)> {
-        let target = rec.p + rec.normal + random_in_unit_sphere();
-        let scattered = Ray::new(rec.p, target - rec.p, r_in.time());
-        let attenuation = self.albedo.value(rec.u, rec.v, &rec.p);
-        Scattered::Yes((scattered, attenuation))
+    pub fn sample(&self, rec: &HitRecord, sampler: &mut Sampler) -> Option<BsdfSample> {
+        // A point on the unit sphere rather than in it gives exactly cosine
+        // weighted directions.
+        let mut direction = rec.normal + sample_unit_vector(sampler.next_2d());
+        if direction.squared_length() < 1e-8 {
+            direction = rec.normal;
+        }
+        let direction = unit_vector(direction);
+        Some(BsdfSample {
+            direction,
+            weight: self.albedo.value_at(rec),
+            pdf: self.pdf(rec, &direction),
+            delta: false,
+        })
+    }
+
+    pub fn eval(&self, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        self.albedo.value_at(rec) * self.pdf(rec, direction)
+    }
+
+    pub fn pdf(&self, rec: &HitRecord, direction: &Vec3) -> f32 {
+        dot(&rec.normal, direction).max(0.0) / f32::consts::PI
     }
 }
 
+// Glass and other clear materials, reflecting or refracting by the Fresnel
+// equations. A rough surface spreads both with GGX microfacets, for frosted
+// glass. Light travelling through the inside is absorbed by Beer-Lambert's
+// law, the absorption coefficient being the share lost per unit of
+// distance in each channel, so thick parts of tinted glass get darker.
 #[derive(Clone, Debug)]
 pub struct Dielectric {
     ref_idx: f32,
+    distribution: Ggx,
+    absorption: Vec3,
 }
 
 impl Dielectric {
     pub fn new(ref_idx: f32) -> Dielectric {
-        Dielectric { ref_idx: ref_idx }
-    }
-
-    pub fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Scattered<(Ray, Vec3)> {
-        let outward_normal: Vec3;
-        let reflected = reflect(&r_in.direction(), &rec.normal);
-        let ni_over_nt: f32;
-        let attenuation = Vec3(1.0, 1.0, 1.0);
-        let mut refracted: Vec3 = Vec3(0.0, 0.0, 0.0);
-        let reflect_prob: f32;
-        let cosine: f32;
-        let scattered: Ray;
+        Dielectric::rough(ref_idx, 0.0, Vec3(0.0, 0.0, 0.0))
+    }
+
+    pub fn rough(ref_idx: f32, roughness: f32, absorption: Vec3) -> Dielectric {
+        Dielectric {
+            ref_idx,
+            distribution: Ggx::new(roughness, roughness),
+            absorption,
+        }
+    }
+
+    // Frame with the normal on the viewer's side and the direction to the
+    // viewer in it, and the index of refraction of the far side relative to
+    // the viewer's side.
+    fn frame(&self, r_in: &Ray, rec: &HitRecord) -> (Frame, Vec3, f32) {
+        let (frame, wo) = shading_frame(r_in, rec);
         if dot(&r_in.direction(), &rec.normal) > 0.0 {
-            outward_normal = rec.normal * (-1.0);
-            ni_over_nt = self.ref_idx;
-            cosine = self.ref_idx + dot(&r_in.direction(), &rec.normal) / r_in.direction().length();
+            (frame, wo, 1.0 / self.ref_idx)
         } else {
-            outward_normal = rec.normal;
-            ni_over_nt = 1.0 / self.ref_idx;
-            cosine = -dot(&r_in.direction(), &rec.normal) / r_in.direction().length();
-        }
-        match refract(&r_in.direction(), &outward_normal, ni_over_nt) {
-            Some(refr) => {
-                refracted = refr;
-                reflect_prob = schlick(cosine, self.ref_idx);
-            }
-            None => {
-                reflect_prob = 1.0;
-            }
+            (frame, wo, self.ref_idx)
         }
-        let mut rng = thread_rng();
-        if rng.gen::<f32>() < reflect_prob {
-            scattered = Ray::new(rec.p, reflected, r_in.time());
+    }
+
+    // Reflects with the Fresnel reflectance and refracts otherwise, about a
+    // visible microfacet normal on a rough surface. Choosing by Fresnel
+    // leaves only the shadowing in the weight.
+    pub fn sample(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut Sampler) -> Option<BsdfSample> {
+        let (frame, wo, eta) = self.frame(r_in, rec);
+        let smooth = self.distribution.is_smooth();
+        let m = if smooth {
+            Vec3(0.0, 0.0, 1.0)
         } else {
-            scattered = Ray::new(rec.p, refracted, r_in.time());
+            self.distribution.sample_visible(&wo, sampler.next_2d())
+        };
+        let cos_m = dot(&wo, &m);
+        let fresnel = fresnel_dielectric(cos_m, eta);
+        let reflecting = sampler.next_1d() < fresnel;
+        let wi = if reflecting {
+            reflect_about(&wo, &m)
+        } else {
+            refract_about(&wo, &m, eta)?
+        };
+        if smooth {
+            return Some(BsdfSample {
+                direction: frame.to_world(&wi),
+                weight: Vec3(1.0, 1.0, 1.0),
+                pdf: 0.0,
+                delta: true,
+            });
+        }
+        if reflecting != (wi.z() > 0.0) {
+            return None;
+        }
+        let shadowing = self.distribution.g(&wo, &wi) / self.distribution.g1(&wo);
+        Some(BsdfSample {
+            direction: frame.to_world(&wi),
+            weight: Vec3(shadowing, shadowing, shadowing),
+            pdf: self.pdf_local(&wo, &wi, eta),
+            delta: false,
+        })
+    }
+
+    pub fn eval(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        let (frame, wo, eta) = self.frame(r_in, rec);
+        let wi = frame.to_local(direction);
+        let m = match self.half_vector(&wo, &wi, eta) {
+            Some(m) => m,
+            None => return Vec3(0.0, 0.0, 0.0),
+        };
+        let fresnel = fresnel_dielectric(dot(&wo, &m), eta);
+        let dg = self.distribution.d(&m) * self.distribution.g(&wo, &wi);
+        let value = if wi.z() > 0.0 {
+            fresnel * dg / (4.0 * wo.z())
+        } else {
+            let denominator = dot(&wo, &m) + eta * dot(&wi, &m);
+            (1.0 - fresnel) * dg * dot(&wo, &m) * eta * eta * dot(&wi, &m).abs()
+                / (wo.z() * denominator * denominator)
+        };
+        Vec3(value, value, value)
+    }
+
+    pub fn pdf(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> f32 {
+        let (frame, wo, eta) = self.frame(r_in, rec);
+        self.pdf_local(&wo, &frame.to_local(direction), eta)
+    }
+
+    fn pdf_local(&self, wo: &Vec3, wi: &Vec3, eta: f32) -> f32 {
+        let m = match self.half_vector(wo, wi, eta) {
+            Some(m) => m,
+            None => return 0.0,
+        };
+        let cos_m = dot(wo, &m);
+        let fresnel = fresnel_dielectric(cos_m, eta);
+        let visible = self.distribution.visible_pdf(wo, &m);
+        if wi.z() > 0.0 {
+            fresnel * visible / (4.0 * cos_m)
+        } else {
+            let denominator = cos_m + eta * dot(wi, &m);
+            (1.0 - fresnel) * visible * eta * eta * dot(wi, &m).abs()
+                / (denominator * denominator)
         }
-        Scattered::Yes((scattered, attenuation))
+    }
+
+    // The microfacet normal that reflects or refracts wo into wi. None for
+    // smooth surfaces, which only have delta lobes, and for pairs no
+    // microfacet connects.
+    fn half_vector(&self, wo: &Vec3, wi: &Vec3, eta: f32) -> Option<Vec3> {
+        if self.distribution.is_smooth() || wo.z() <= 0.0 || wi.z() == 0.0 {
+            None
+        } else if wi.z() > 0.0 {
+            Some(unit_vector(*wo + *wi))
+        } else {
+            refraction_half_vector(wo, wi, eta)
+        }
+    }
+
+    // Beer-Lambert transmittance of the path a ray took to rec through the
+    // inside.
+    pub fn transmittance(&self, r_in: &Ray, rec: &HitRecord) -> Vec3 {
+        if dot(&r_in.direction(), &rec.normal) <= 0.0 {
+            return Vec3(1.0, 1.0, 1.0);
+        }
+        let distance = rec.t * r_in.direction().length();
+        Vec3(
+            (-self.absorption.x() * distance).exp(),
+            (-self.absorption.y() * distance).exp(),
+            (-self.absorption.z() * distance).exp(),
+        )
     }
 }
 
-pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
-    *v - *n * (dot(v, n) * 2.0)
+// Surface detail that tilts the shading normal without moving the surface.
+//
+// normal: a texture holding normals in the tangent frame, with x along u, y
+//   along v and z along the surface normal, each mapped from [-1, 1] to
+//   [0, 1] as is usual for normal map images.
+// bump: a height field, the mean of the texture's channels times a scale in
+//   scene units, whose slope tilts the normal.
+#[derive(Clone, Debug)]
+pub enum SurfaceMap {
+    Normal(Texture),
+    Bump(Texture, f32),
 }
 
-pub fn refract(v: &Vec3, n: &Vec3, ni_over_nt: f32) -> Option<Vec3> {
-    let uv = unit_vector(*v);
-    let dt = dot(&uv, n);
-    let discriminant = 1.0 - ni_over_nt * ni_over_nt * (1.0 - dt * dt);
-    if discriminant > 0.0 {
-        Some((uv - *n * dt) * ni_over_nt - *n * discriminant.sqrt())
-    } else {
-        None
+// Step in u and v for the finite differences of a bump map.
+const BUMP_DELTA: f32 = 0.0005;
+
+// Another material with a surface map applied to it, which only sees the
+// perturbed normal. Shapes without u and v derivatives are left flat.
+#[derive(Clone, Debug)]
+pub struct Mapped {
+    material: Box<Material>,
+    map: SurfaceMap,
+}
+
+impl Mapped {
+    pub fn new(material: Material, map: SurfaceMap) -> Mapped {
+        Mapped {
+            material: Box::new(material),
+            map,
+        }
+    }
+
+    fn shade<'a>(&self, rec: &HitRecord<'a>) -> HitRecord<'a> {
+        let mut shaded = rec.clone();
+        let normal = match self.map {
+            SurfaceMap::Normal(ref texture) => mapped_normal(texture, rec),
+            SurfaceMap::Bump(ref texture, scale) => bumped_normal(texture, scale, rec),
+        };
+        if let Some(normal) = normal {
+            shaded.normal = normal;
+        }
+        shaded
+    }
+}
+
+// The normal, dpdu made perpendicular to it and the bitangent between them
+// pointing along dpdv.
+fn tangent_frame(rec: &HitRecord) -> Option<Frame> {
+    let n = rec.normal;
+    let tangent = rec.dpdu - n * dot(&n, &rec.dpdu);
+    if tangent.squared_length() < 1e-12 {
+        return None;
+    }
+    let s = unit_vector(tangent);
+    let t = cross(&n, &s);
+    let t = if dot(&t, &rec.dpdv) < 0.0 { t * -1.0 } else { t };
+    Some(Frame { s, t, n })
+}
+
+fn mapped_normal(texture: &Texture, rec: &HitRecord) -> Option<Vec3> {
+    let frame = tangent_frame(rec)?;
+    let local = texture.value_at(rec) * 2.0 - Vec3(1.0, 1.0, 1.0);
+    if local.squared_length() == 0.0 {
+        return None;
     }
+    Some(unit_vector(frame.to_world(&local)))
 }
 
-pub fn schlick(cosine: f32, ref_idx: f32) -> f32 {
-    let mut r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
-    r0 = r0 * r0;
-    r0 + (1.0 - r0) * (1.0 - cosine).powf(5.0)
+// Mikkelsen's surface gradient, "Bump Mapping Unparametrized Surfaces on the
+// GPU": the normal of the surface moved along it by the height, to first
+// order. Keeps interpolated mesh normals and works for either handedness of
+// dpdu and dpdv.
+fn bumped_normal(texture: &Texture, scale: f32, rec: &HitRecord) -> Option<Vec3> {
+    let n = rec.normal;
+    let det = dot(&n, &cross(&rec.dpdu, &rec.dpdv));
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let height = |u: f32, v: f32, p: &Vec3| {
+        let value = texture.value(u, v, p);
+        scale * (value.x() + value.y() + value.z()) / 3.0
+    };
+    let base = height(rec.u, rec.v, &rec.p);
+    let along_u = height(rec.u + BUMP_DELTA, rec.v, &(rec.p + rec.dpdu * BUMP_DELTA));
+    let along_v = height(rec.u, rec.v + BUMP_DELTA, &(rec.p + rec.dpdv * BUMP_DELTA));
+    let slope_u = (along_u - base) / BUMP_DELTA;
+    let slope_v = (along_v - base) / BUMP_DELTA;
+    let gradient = (cross(&rec.dpdv, &n) * slope_u + cross(&n, &rec.dpdu) * slope_v) * (1.0 / det);
+    Some(unit_vector(n - gradient))
+}
+
+pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
+    *v - *n * (dot(v, n) * 2.0)
 }
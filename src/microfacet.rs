@@ -0,0 +1,213 @@
+use std::f32;
+
+use vector::*;
+use utils::orthonormal_basis;
+
+// Orthonormal basis around a shading normal, in which the normal is z.
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    pub s: Vec3,
+    pub t: Vec3,
+    pub n: Vec3,
+}
+
+impl Frame {
+    pub fn from_normal(n: Vec3) -> Frame {
+        let (s, t) = orthonormal_basis(&n);
+        Frame { s, t, n }
+    }
+
+    // A frame with s along tangent, made perpendicular to n, so anisotropic
+    // lobes follow the surface. Any frame will do where tangent is zero or
+    // parallel to n.
+    pub fn from_tangent(n: Vec3, tangent: &Vec3) -> Frame {
+        let s = *tangent - n * dot(&n, tangent);
+        if s.squared_length() <= 1e-12 * tangent.squared_length() {
+            return Frame::from_normal(n);
+        }
+        let s = unit_vector(s);
+        Frame {
+            s,
+            t: cross(&n, &s),
+            n,
+        }
+    }
+
+    pub fn to_local(self, v: &Vec3) -> Vec3 {
+        Vec3(dot(v, &self.s), dot(v, &self.t), dot(v, &self.n))
+    }
+
+    pub fn to_world(self, v: &Vec3) -> Vec3 {
+        self.s * v.x() + self.t * v.y() + self.n * v.z()
+    }
+}
+
+// Below this roughness a surface is drawn as a perfect mirror, the GGX
+// lobe gets too narrow for floats.
+const SMOOTH_ALPHA: f32 = 1e-3;
+
+// The GGX (Trowbridge-Reitz) distribution of microfacet normals with a
+// separate roughness along the frame's s and t axes, and the height
+// correlated Smith shadowing that goes with it. Directions are in the local
+// frame with the normal along z.
+#[derive(Clone, Copy, Debug)]
+pub struct Ggx {
+    alpha_x: f32,
+    alpha_y: f32,
+}
+
+impl Ggx {
+    // Roughness is squared into alpha, which spreads the look of the values
+    // more evenly between 0 and 1.
+    pub fn new(roughness_x: f32, roughness_y: f32) -> Ggx {
+        Ggx {
+            alpha_x: roughness_x * roughness_x,
+            alpha_y: roughness_y * roughness_y,
+        }
+    }
+
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < SMOOTH_ALPHA
+    }
+
+    // Density of microfacet normal m per unit of projected area.
+    pub fn d(&self, m: &Vec3) -> f32 {
+        if m.z() <= 0.0 {
+            return 0.0;
+        }
+        let x = m.x() / self.alpha_x;
+        let y = m.y() / self.alpha_y;
+        let denominator = x * x + y * y + m.z() * m.z();
+        1.0 / (f32::consts::PI * self.alpha_x * self.alpha_y * denominator * denominator)
+    }
+
+    fn lambda(&self, w: &Vec3) -> f32 {
+        let z2 = w.z() * w.z();
+        if z2 == 0.0 {
+            return f32::INFINITY;
+        }
+        let ax = w.x() * self.alpha_x;
+        let ay = w.y() * self.alpha_y;
+        let tan2 = (ax * ax + ay * ay) / z2;
+        ((1.0 + tan2).sqrt() - 1.0) / 2.0
+    }
+
+    // Share of the microfacets facing w that w sees.
+    pub fn g1(&self, w: &Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Share of microfacets both directions see.
+    pub fn g(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Density of the normals sample_visible picks, per solid angle of m.
+    pub fn visible_pdf(&self, wo: &Vec3, m: &Vec3) -> f32 {
+        if wo.z() == 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * dot(wo, m).max(0.0) * self.d(m) / wo.z().abs()
+    }
+
+    // Heitz, "Sampling the GGX Distribution of Visible Normals": a normal
+    // in proportion to how much of the surface it covers as seen from wo,
+    // which has to be above the surface.
+    pub fn sample_visible(&self, wo: &Vec3, u: (f32, f32)) -> Vec3 {
+        let stretched = unit_vector(Vec3(self.alpha_x * wo.x(), self.alpha_y * wo.y(), wo.z()));
+        let length2 = stretched.x() * stretched.x() + stretched.y() * stretched.y();
+        let t1 = if length2 > 0.0 {
+            Vec3(-stretched.y(), stretched.x(), 0.0) * (1.0 / length2.sqrt())
+        } else {
+            Vec3(1.0, 0.0, 0.0)
+        };
+        let t2 = cross(&stretched, &t1);
+        let r = u.0.sqrt();
+        let phi = 2.0 * f32::consts::PI * u.1;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + stretched.z());
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+        let p3 = (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+        let m = t1 * p1 + t2 * p2 + stretched * p3;
+        unit_vector(Vec3(
+            self.alpha_x * m.x(),
+            self.alpha_y * m.y(),
+            m.z().max(1e-6),
+        ))
+    }
+}
+
+// Mirror image of w around m, both pointing away from the surface.
+pub fn reflect_about(w: &Vec3, m: &Vec3) -> Vec3 {
+    *m * (2.0 * dot(w, m)) - *w
+}
+
+// Fresnel reflectance of a conductor with complex index of refraction
+// eta + ik per channel, for light arriving at cos_theta to the normal.
+pub fn fresnel_conductor(cos_theta: f32, eta: &Vec3, k: &Vec3) -> Vec3 {
+    let channel = |eta: f32, k: f32| {
+        let cos2 = cos_theta.clamp(0.0, 1.0).powi(2);
+        let sin2 = 1.0 - cos2;
+        let eta2 = eta * eta;
+        let k2 = k * k;
+        let t0 = eta2 - k2 - sin2;
+        let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+        let t1 = a2_plus_b2 + cos2;
+        let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+        let t2 = 2.0 * cos_theta * a;
+        let rs = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+        0.5 * (rp + rs)
+    };
+    Vec3(
+        channel(eta.x(), k.x()),
+        channel(eta.y(), k.y()),
+        channel(eta.z(), k.z()),
+    )
+}
+
+// Direction w refracts into through microfacet m, for a relative index of
+// refraction eta of the far side. None on total internal reflection.
+pub fn refract_about(w: &Vec3, m: &Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = dot(w, m);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(*m * (cos_i / eta - cos_t) - *w * (1.0 / eta))
+}
+
+// The microfacet normal on w's side that refracts w into wt, for a relative
+// index of refraction eta of wt's side. None if refraction through no
+// microfacet connects them.
+pub fn refraction_half_vector(w: &Vec3, wt: &Vec3, eta: f32) -> Option<Vec3> {
+    let m = (*w + *wt * eta) * -1.0;
+    if m.squared_length() == 0.0 {
+        return None;
+    }
+    let m = unit_vector(m);
+    let m = if m.z() < 0.0 { m * -1.0 } else { m };
+    // Refraction has to go through the microfacet, not bounce off it.
+    if dot(w, &m) <= 0.0 || dot(wt, &m) >= 0.0 {
+        return None;
+    }
+    Some(m)
+}
+
+// Fresnel reflectance of unpolarized light arriving at cos_i to the normal
+// of an interface to a medium with relative index of refraction eta. One
+// on total internal reflection.
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
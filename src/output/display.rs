@@ -0,0 +1,117 @@
+use vector::Vec3;
+use sampler::hash_to_unit;
+use output::Framebuffer;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneMap {
+    Clamp,
+    Reinhard,
+    Aces,
+    Filmic,
+}
+
+impl ToneMap {
+    pub fn from_name(name: &str) -> Option<ToneMap> {
+        match name {
+            "clamp" => Some(ToneMap::Clamp),
+            "reinhard" => Some(ToneMap::Reinhard),
+            "aces" => Some(ToneMap::Aces),
+            "filmic" => Some(ToneMap::Filmic),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ToneMap::Clamp => "clamp",
+            ToneMap::Reinhard => "reinhard",
+            ToneMap::Aces => "aces",
+            ToneMap::Filmic => "filmic",
+        }
+    }
+
+    // Maps linear radiance to display values in [0, 1].
+    //
+    // clamp: leaves the image alone and clips everything above 1.
+    // reinhard: scales colors by 1 / (1 + luminance), which keeps their hue
+    //   and rolls highlights off without ever reaching white.
+    // aces: Narkowicz's fit of the ACES filmic curve per channel, with more
+    //   contrast and highlights that desaturate to white.
+    // filmic: Hable's curve from Uncharted 2, with a toe in the shadows and
+    //   a white point 11.2 times the exposure.
+    fn apply(&self, color: Vec3) -> Vec3 {
+        match *self {
+            ToneMap::Clamp => color,
+            ToneMap::Reinhard => {
+                let luminance = 0.2126 * color.r() + 0.7152 * color.g() + 0.0722 * color.b();
+                color * (1.0 / (1.0 + luminance.max(0.0)))
+            }
+            ToneMap::Aces => map_channels(color, |x| {
+                (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+            }),
+            ToneMap::Filmic => {
+                let white = hable(11.2);
+                map_channels(color, |x| hable(2.0 * x) / white)
+            }
+        }
+    }
+}
+
+fn map_channels<F: Fn(f32) -> f32>(color: Vec3, f: F) -> Vec3 {
+    Vec3(f(color.r()), f(color.g()), f(color.b()))
+}
+
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+// Turns the linear framebuffer into 8 bit sRGB for the formats that can't
+// hold more: scales it by 2^exposure, tone maps it, encodes it with the sRGB
+// transfer function and rounds it to bytes. Dithering adds up to one step of
+// triangular noise before rounding, which breaks up banding in smooth
+// gradients. The noise only depends on the pixel, so the same image always
+// gives the same bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct DisplayTransform {
+    pub exposure: f32,
+    pub tone_map: ToneMap,
+    pub dither: bool,
+}
+
+impl DisplayTransform {
+    pub fn new() -> DisplayTransform {
+        DisplayTransform {
+            exposure: 0.0,
+            tone_map: ToneMap::Clamp,
+            dither: true,
+        }
+    }
+
+    pub fn encode_rgb8(&self, framebuffer: &Framebuffer) -> Vec<u8> {
+        let scale = self.exposure.exp2();
+        let mut data = Vec::with_capacity(framebuffer.pixels().len() * 3);
+        for (index, pixel) in framebuffer.pixels().iter().enumerate() {
+            let color = self.tone_map.apply(*pixel * scale);
+            for channel in 0..3u8 {
+                let encoded = srgb_encode(color[channel].clamp(0.0, 1.0));
+                let noise = if self.dither {
+                    let key = index as u64 * 3 + u64::from(channel);
+                    hash_to_unit(&[key, 0]) + hash_to_unit(&[key, 1]) - 1.0
+                } else {
+                    0.0
+                };
+                data.push((encoded * 255.0 + noise).round().clamp(0.0, 255.0) as u8);
+            }
+        }
+        data
+    }
+}
+
+fn srgb_encode(linear: f32) -> f32 {
+    if linear <= 0.003_130_8 {
+        12.92 * linear
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
@@ -0,0 +1,108 @@
+use std::io::{self, Write};
+
+use output::Framebuffer;
+
+const MAGIC: u32 = 20_000_630;
+const VERSION: u32 = 2;
+const PIXEL_TYPE_FLOAT: i32 = 2;
+const NO_COMPRESSION: u8 = 0;
+const INCREASING_Y: u8 = 0;
+
+// Single part, scanline OpenEXR image with uncompressed 32-bit float R, G and
+// B channels holding the linear radiance.
+#[derive(Clone, Debug)]
+pub struct ExrWriter;
+
+impl ExrWriter {
+    pub fn new() -> ExrWriter {
+        ExrWriter
+    }
+
+    pub fn write<W: Write>(&self, framebuffer: &Framebuffer, out: &mut W) -> io::Result<()> {
+        let width = framebuffer.width() as usize;
+        let height = framebuffer.height() as usize;
+        let window = [0, 0, width as i32 - 1, height as i32 - 1];
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&MAGIC.to_le_bytes());
+        header.extend_from_slice(&VERSION.to_le_bytes());
+
+        // Channels have to be listed in alphabetical order.
+        let mut channels = Vec::new();
+        for name in &["B", "G", "R"] {
+            channels.extend_from_slice(name.as_bytes());
+            channels.push(0);
+            channels.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+            channels.extend_from_slice(&[0, 0, 0, 0]);
+            channels.extend_from_slice(&1i32.to_le_bytes());
+            channels.extend_from_slice(&1i32.to_le_bytes());
+        }
+        channels.push(0);
+        write_attribute(&mut header, "channels", "chlist", &channels);
+        write_attribute(&mut header, "compression", "compression", &[NO_COMPRESSION]);
+        write_attribute(&mut header, "dataWindow", "box2i", &box2i(&window));
+        write_attribute(&mut header, "displayWindow", "box2i", &box2i(&window));
+        write_attribute(&mut header, "lineOrder", "lineOrder", &[INCREASING_Y]);
+        write_attribute(
+            &mut header,
+            "pixelAspectRatio",
+            "float",
+            &1.0f32.to_le_bytes(),
+        );
+        let mut center = Vec::new();
+        center.extend_from_slice(&0.0f32.to_le_bytes());
+        center.extend_from_slice(&0.0f32.to_le_bytes());
+        write_attribute(&mut header, "screenWindowCenter", "v2f", &center);
+        write_attribute(
+            &mut header,
+            "screenWindowWidth",
+            "float",
+            &1.0f32.to_le_bytes(),
+        );
+        header.push(0);
+
+        // Without compression every scanline is its own chunk, preceded by its
+        // y coordinate and the size of the pixel data.
+        let line_size = width * 3 * 4;
+        let chunk_size = 8 + line_size;
+        let first_chunk = header.len() + height * 8;
+        let mut offsets = Vec::with_capacity(height * 8);
+        for y in 0..height {
+            let offset = (first_chunk + y * chunk_size) as u64;
+            offsets.extend_from_slice(&offset.to_le_bytes());
+        }
+        out.write_all(&header)?;
+        out.write_all(&offsets)?;
+
+        let mut chunk = Vec::with_capacity(chunk_size);
+        for (y, row) in framebuffer.pixels().chunks(width).enumerate() {
+            chunk.clear();
+            chunk.extend_from_slice(&(y as i32).to_le_bytes());
+            chunk.extend_from_slice(&(line_size as u32).to_le_bytes());
+            for channel in 0..3 {
+                for pixel in row {
+                    chunk.extend_from_slice(&pixel[2 - channel].to_le_bytes());
+                }
+            }
+            out.write_all(&chunk)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+fn box2i(window: &[i32; 4]) -> Vec<u8> {
+    let mut value = Vec::with_capacity(16);
+    for coordinate in window {
+        value.extend_from_slice(&coordinate.to_le_bytes());
+    }
+    value
+}
@@ -0,0 +1,109 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+pub mod ppm;
+pub mod png;
+pub mod pfm;
+pub mod exr;
+pub mod display;
+
+use vector::Vec3;
+pub use self::ppm::*;
+pub use self::png::*;
+pub use self::pfm::*;
+pub use self::exr::*;
+pub use self::display::*;
+
+#[derive(Clone, Debug)]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    // Linear radiance, stored row by row starting from the top left corner.
+    pixels: Vec<Vec3>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![Vec3(0.0, 0.0, 0.0); (width * height) as usize],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[Vec3] {
+        &self.pixels
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, color: Vec3) {
+        self.pixels[(y * self.width + x) as usize] = color;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ImageWriter {
+    Ppm(PpmWriter),
+    Png(PngWriter),
+    Pfm(PfmWriter),
+    Exr(ExrWriter),
+}
+
+impl ImageWriter {
+    // The display transform only applies to the 8 bit formats, the float
+    // ones keep the linear radiance.
+    pub fn for_path(path: &Path, display: DisplayTransform) -> Result<ImageWriter, String> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        match extension.as_deref() {
+            Some("ppm") => Ok(ImageWriter::Ppm(PpmWriter::new(display))),
+            Some("png") => Ok(ImageWriter::Png(PngWriter::new(display))),
+            Some("pfm") => Ok(ImageWriter::Pfm(PfmWriter::new())),
+            Some("exr") => Ok(ImageWriter::Exr(ExrWriter::new())),
+            _ => Err(format!(
+                "unsupported image format for {}, expected .ppm, .png, .pfm or .exr",
+                path.display()
+            )),
+        }
+    }
+
+    pub fn write<W: Write>(&self, framebuffer: &Framebuffer, out: &mut W) -> io::Result<()> {
+        match *self {
+            ImageWriter::Ppm(ref ppm) => ppm.write(framebuffer, out),
+            ImageWriter::Png(ref png) => png.write(framebuffer, out),
+            ImageWriter::Pfm(ref pfm) => pfm.write(framebuffer, out),
+            ImageWriter::Exr(ref exr) => exr.write(framebuffer, out),
+        }
+    }
+
+    pub fn save(&self, framebuffer: &Framebuffer, path: &Path) -> io::Result<()> {
+        write_atomically(path, |out| self.write(framebuffer, out))
+    }
+}
+
+// Writes next to the target and renames it into place, so anything watching
+// the file during a progressive render never reads half of it, and a crash
+// while writing leaves the previous version intact.
+pub fn write_atomically<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let mut out = BufWriter::new(File::create(&partial)?);
+    write(&mut out)?;
+    out.flush()?;
+    drop(out);
+    fs::rename(&partial, path)
+}
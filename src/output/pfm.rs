@@ -0,0 +1,34 @@
+use std::io::{self, Write};
+
+use output::Framebuffer;
+
+// Portable float map with three little endian 32-bit floats per pixel. Rows
+// are stored bottom to top and the radiance is written without any clamping
+// or gamma correction.
+#[derive(Clone, Debug)]
+pub struct PfmWriter;
+
+impl PfmWriter {
+    pub fn new() -> PfmWriter {
+        PfmWriter
+    }
+
+    pub fn write<W: Write>(&self, framebuffer: &Framebuffer, out: &mut W) -> io::Result<()> {
+        let width = framebuffer.width() as usize;
+        write!(
+            out,
+            "PF\n{} {}\n-1.0\n",
+            framebuffer.width(),
+            framebuffer.height()
+        )?;
+        let mut data = Vec::with_capacity(framebuffer.pixels().len() * 12);
+        for row in framebuffer.pixels().chunks(width).rev() {
+            for pixel in row {
+                data.extend_from_slice(&pixel.r().to_le_bytes());
+                data.extend_from_slice(&pixel.g().to_le_bytes());
+                data.extend_from_slice(&pixel.b().to_le_bytes());
+            }
+        }
+        out.write_all(&data)
+    }
+}
@@ -0,0 +1,30 @@
+use std::io::{self, Write};
+use png::{BitDepth, ColorType, Encoder};
+
+use output::{DisplayTransform, Framebuffer};
+
+#[derive(Clone, Debug)]
+pub struct PngWriter {
+    display: DisplayTransform,
+}
+
+impl PngWriter {
+    pub fn new(display: DisplayTransform) -> PngWriter {
+        PngWriter { display }
+    }
+
+    pub fn write<W: Write>(&self, framebuffer: &Framebuffer, out: &mut W) -> io::Result<()> {
+        let mut encoder = Encoder::new(out, framebuffer.width(), framebuffer.height());
+        encoder.set_color(ColorType::Rgb);
+        encoder.set_depth(BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(to_io_error)?;
+        writer
+            .write_image_data(&self.display.encode_rgb8(framebuffer))
+            .map_err(to_io_error)?;
+        writer.finish().map_err(to_io_error)
+    }
+}
+
+fn to_io_error(err: ::png::EncodingError) -> io::Error {
+    io::Error::other(err)
+}
@@ -0,0 +1,25 @@
+use std::io::{self, Write};
+
+use output::{DisplayTransform, Framebuffer};
+
+// Binary (P6) portable pixmap with 8 bits per channel.
+#[derive(Clone, Debug)]
+pub struct PpmWriter {
+    display: DisplayTransform,
+}
+
+impl PpmWriter {
+    pub fn new(display: DisplayTransform) -> PpmWriter {
+        PpmWriter { display }
+    }
+
+    pub fn write<W: Write>(&self, framebuffer: &Framebuffer, out: &mut W) -> io::Result<()> {
+        write!(
+            out,
+            "P6\n{} {}\n255\n",
+            framebuffer.width(),
+            framebuffer.height()
+        )?;
+        out.write_all(&self.display.encode_rgb8(framebuffer))
+    }
+}
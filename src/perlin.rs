@@ -1,4 +1,4 @@
-use rand::{thread_rng, Rng};
+use rand::Rng;
 use std::fmt::{Debug, Formatter, Result};
 
 use vector::*;
@@ -60,12 +60,12 @@ impl Debug for Perlin {
 }
 
 impl Perlin {
-    pub fn new() -> Perlin {
+    pub fn new<R: Rng>(rng: &mut R) -> Perlin {
         Perlin {
-            perm_x: perlin_generate_perm(),
-            perm_y: perlin_generate_perm(),
-            perm_z: perlin_generate_perm(),
-            ranvec: perlin_generate(),
+            perm_x: perlin_generate_perm(rng),
+            perm_y: perlin_generate_perm(rng),
+            perm_z: perlin_generate_perm(rng),
+            ranvec: perlin_generate(rng),
         }
     }
 
@@ -113,8 +113,7 @@ impl Perlin {
     }
 }
 
-pub fn perlin_generate() -> [Vec3; 256] {
-    let mut rng = thread_rng();
+pub fn perlin_generate<R: Rng>(rng: &mut R) -> [Vec3; 256] {
     let mut p: [Vec3; 256] = [Vec3(0.0, 0.0, 0.0); 256];
     for i in 0..256 {
         p[i] = unit_vector(Vec3(
@@ -126,8 +125,7 @@ pub fn perlin_generate() -> [Vec3; 256] {
     p
 }
 
-pub fn permute(p: &mut [u32], n: u32) {
-    let mut rng = thread_rng();
+pub fn permute<R: Rng>(p: &mut [u32], n: u32, rng: &mut R) {
     for i in (1..n).rev() {
         let target = (rng.gen::<f32>() * (i + 1) as f32) as u32;
         let temp = p[i as usize];
@@ -136,11 +134,11 @@ pub fn permute(p: &mut [u32], n: u32) {
     }
 }
 
-pub fn perlin_generate_perm() -> [u32; 256] {
+pub fn perlin_generate_perm<R: Rng>(rng: &mut R) -> [u32; 256] {
     let mut p: [u32; 256] = [0; 256];
     for i in 0..256 {
         p[i as usize] = i;
     }
-    permute(&mut p, 256);
+    permute(&mut p, 256, rng);
     p
 }
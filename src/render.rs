@@ -0,0 +1,784 @@
+use std::cmp::Ordering;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use std::time::Instant;
+
+use vector::*;
+use hitable::*;
+use camera::*;
+use light::*;
+use integrator::*;
+use sampler::*;
+use filter::*;
+use output::Framebuffer;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub samples: u32,
+    pub max_depth: u32,
+    pub roulette: Roulette,
+    pub integrator: Integrator,
+    pub sampler: SamplerKind,
+    pub seed: u64,
+    pub region: Region,
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+    pub adaptive: Option<Adaptive>,
+    pub filter: Filter,
+    pub indirect_clamp: Option<f32>,
+    pub debug_invalid: bool,
+}
+
+// Adaptive sampling spends the same budget of samples per pixel on average,
+// but stops pixels whose error has dropped below the threshold once they
+// have min_samples, leaving more for noisy pixels, up to max_samples.
+#[derive(Clone, Copy, Debug)]
+pub struct Adaptive {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub threshold: f32,
+}
+
+impl RenderSettings {
+    // The longest sample sequence any pixel goes through.
+    pub fn max_samples(&self) -> u32 {
+        self.adaptive
+            .map_or(self.samples, |adaptive| adaptive.max_samples)
+    }
+
+    // Everything that changes the rendered pixels. Tiles and passes only
+    // change the order the work is done in, and with a wide filter the
+    // rounding, and are left out.
+    pub fn describe(&self) -> String {
+        let adaptive = match self.adaptive {
+            Some(adaptive) => format!(
+                " adaptive {} {} {:?}",
+                adaptive.min_samples, adaptive.max_samples, adaptive.threshold
+            ),
+            None => String::new(),
+        };
+        let clamp = match self.indirect_clamp {
+            Some(clamp) => format!(" clamp {:?}", clamp),
+            None => String::new(),
+        };
+        format!(
+            "{}x{} region {},{},{}x{} spp {}{} depth {} roulette {} {:?} integrator {} sampler {} \
+             seed {} filter {} {:?}{}",
+            self.width,
+            self.height,
+            self.region.x,
+            self.region.y,
+            self.region.width,
+            self.region.height,
+            self.samples,
+            adaptive,
+            self.max_depth,
+            self.roulette.depth,
+            self.roulette.min_survival,
+            self.integrator.name(),
+            self.sampler.name(),
+            self.seed,
+            self.filter.kind.name(),
+            self.filter.radius,
+            clamp
+        )
+    }
+}
+
+// Part of the frame to render, in pixels from the top left corner. Pixels
+// are seeded by their place in the whole frame, so a region comes out exactly
+// like the same pixels of a full render. Filters wider than a pixel are the
+// exception at its edges, which miss the samples from outside the region.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    pub fn full(width: u32, height: u32) -> Region {
+        Region {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileOrder {
+    Scanline,
+    Spiral,
+}
+
+impl TileOrder {
+    pub fn from_name(name: &str) -> Option<TileOrder> {
+        match name {
+            "scanline" => Some(TileOrder::Scanline),
+            "spiral" => Some(TileOrder::Spiral),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TileOrder::Scanline => "scanline",
+            TileOrder::Spiral => "spiral",
+        }
+    }
+}
+
+// A rectangle of film pixels that one thread renders start to finish.
+#[derive(Clone, Copy, Debug)]
+struct Tile {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Tile {
+    fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+
+    fn contains_signed(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && self.contains(x as u32, y as u32)
+    }
+}
+
+// Scanline goes row by row from the top, spiral starts in the middle of the
+// region and walks out ring by ring, so the interesting part usually shows
+// up first.
+fn tiles(region: &Region, size: u32, order: TileOrder) -> Vec<Tile> {
+    let columns = region.width.div_ceil(size);
+    let rows = region.height.div_ceil(size);
+    let mut tiles = Vec::with_capacity((columns * rows) as usize);
+    for row in 0..rows {
+        for column in 0..columns {
+            let x = column * size;
+            let y = row * size;
+            tiles.push(Tile {
+                x,
+                y,
+                width: size.min(region.width - x),
+                height: size.min(region.height - y),
+            });
+        }
+    }
+    if order == TileOrder::Spiral {
+        let center_column = (columns - 1) as f32 / 2.0;
+        let center_row = (rows - 1) as f32 / 2.0;
+        let key = |tile: &Tile| {
+            let dx = (tile.x / size) as f32 - center_column;
+            let dy = (tile.y / size) as f32 - center_row;
+            (dx.abs().max(dy.abs()).round(), dy.atan2(dx))
+        };
+        tiles.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap_or(Ordering::Equal));
+    }
+    tiles
+}
+
+// Running sums of a pixel's own samples and of their squared luminance, for
+// its variance, and how many samples went into them. The pixel's color is
+// the filter weighted sum of every sample that reaches it, over the sum of
+// their weights.
+#[derive(Clone, Copy, Debug)]
+pub struct FilmPixel {
+    pub sum: Vec3,
+    pub squares: f32,
+    pub samples: u32,
+    pub weighted: Vec3,
+    pub weight: f32,
+}
+
+const EMPTY_PIXEL: FilmPixel = FilmPixel {
+    sum: Vec3(0.0, 0.0, 0.0),
+    squares: 0.0,
+    samples: 0,
+    weighted: Vec3(0.0, 0.0, 0.0),
+    weight: 0.0,
+};
+
+impl FilmPixel {
+    // Standard error of the pixel's mean after the display gamma of 2, where
+    // a difference of d(sqrt(l)) = dl / (2 sqrt(l)) is what the eye sees.
+    // The statistics include one imaginary white sample: a few samples that
+    // all came out black or equal say little about a pixel that only rarely
+    // finds the light, so they shouldn't count as converged.
+    pub fn error(&self) -> f32 {
+        if self.samples < 2 {
+            return f32::INFINITY;
+        }
+        let n = self.samples as f32 + 1.0;
+        let mean = (luminance(&self.sum) + 1.0) / n;
+        let variance = ((self.squares + 1.0 - n * mean * mean) / (n - 1.0)).max(0.0);
+        let standard_error = (variance / n).sqrt();
+        standard_error / (2.0 * mean.sqrt())
+    }
+}
+
+fn luminance(color: &Vec3) -> f32 {
+    0.2126 * color.r() + 0.7152 * color.g() + 0.0722 * color.b()
+}
+
+// Every pixel of the region, row by row from the top left corner like the
+// framebuffer. Each pixel continues its own sample sequence from however
+// many samples it already has.
+pub struct Film {
+    width: u32,
+    height: u32,
+    pixels: Vec<FilmPixel>,
+}
+
+impl Film {
+    pub fn new(region: &Region) -> Film {
+        Film {
+            width: region.width,
+            height: region.height,
+            pixels: vec![EMPTY_PIXEL; (region.width * region.height) as usize],
+        }
+    }
+
+    // Film picked up from a checkpoint, None if the pixels don't fill the
+    // region.
+    pub fn from_pixels(region: &Region, pixels: Vec<FilmPixel>) -> Option<Film> {
+        if pixels.len() != (region.width * region.height) as usize {
+            return None;
+        }
+        Some(Film {
+            width: region.width,
+            height: region.height,
+            pixels,
+        })
+    }
+
+    pub fn pixels(&self) -> &[FilmPixel] {
+        &self.pixels
+    }
+
+    pub fn spent(&self) -> u64 {
+        self.pixels
+            .iter()
+            .map(|pixel| u64::from(pixel.samples))
+            .sum()
+    }
+
+    pub fn mean_samples(&self) -> f64 {
+        self.spent() as f64 / self.pixels.len() as f64
+    }
+
+    fn read_tile(&self, tile: &Tile, margin: u32) -> TileFilm {
+        let x = tile.x.saturating_sub(margin);
+        let y = tile.y.saturating_sub(margin);
+        let area = Tile {
+            x,
+            y,
+            width: (tile.x + tile.width + margin).min(self.width) - x,
+            height: (tile.y + tile.height + margin).min(self.height) - y,
+        };
+        let mut pixels = vec![EMPTY_PIXEL; (area.width * area.height) as usize];
+        for row in tile.y..tile.y + tile.height {
+            let start = ((row - area.y) * area.width + tile.x - area.x) as usize;
+            let film_start = (row * self.width + tile.x) as usize;
+            pixels[start..start + tile.width as usize]
+                .copy_from_slice(&self.pixels[film_start..film_start + tile.width as usize]);
+        }
+        TileFilm {
+            tile: *tile,
+            area,
+            pixels,
+        }
+    }
+
+    fn write_tile(&mut self, tile_film: &TileFilm) {
+        let (tile, area) = (&tile_film.tile, &tile_film.area);
+        for row in tile.y..tile.y + tile.height {
+            let start = ((row - area.y) * area.width + tile.x - area.x) as usize;
+            let film_start = (row * self.width + tile.x) as usize;
+            self.pixels[film_start..film_start + tile.width as usize]
+                .copy_from_slice(&tile_film.pixels[start..start + tile.width as usize]);
+        }
+    }
+
+    // Adds what a tile's samples spilled onto the pixels around it.
+    fn add_spill(&mut self, tile_film: &TileFilm) {
+        let (tile, area) = (&tile_film.tile, &tile_film.area);
+        for y in area.y..area.y + area.height {
+            for x in area.x..area.x + area.width {
+                if tile.contains(x, y) {
+                    continue;
+                }
+                let spilled = &tile_film.pixels[tile_film.index(x, y)];
+                let pixel = &mut self.pixels[(y * self.width + x) as usize];
+                pixel.weighted = pixel.weighted + spilled.weighted;
+                pixel.weight += spilled.weight;
+            }
+        }
+    }
+
+    pub fn to_framebuffer(&self) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(self.width, self.height);
+        for (index, pixel) in self.pixels.iter().enumerate() {
+            let index = index as u32;
+            let color = if pixel.weight > 0.0 {
+                pixel.weighted * (1.0 / pixel.weight)
+            } else {
+                Vec3(0.0, 0.0, 0.0)
+            };
+            // Negative lobes can ring below zero next to bright edges.
+            let color = Vec3(
+                not_negative(color.r()),
+                not_negative(color.g()),
+                not_negative(color.b()),
+            );
+            framebuffer.set(index % self.width, index / self.width, color);
+        }
+        framebuffer
+    }
+
+    // Samples spent per pixel as a black, red, yellow, white ramp up to
+    // max_samples.
+    pub fn heatmap(&self, max_samples: u32) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(self.width, self.height);
+        for (index, pixel) in self.pixels.iter().enumerate() {
+            let index = index as u32;
+            let t = 3.0 * pixel.samples as f32 / max_samples.max(1) as f32;
+            let color = Vec3(
+                t.clamp(0.0, 1.0),
+                (t - 1.0).clamp(0.0, 1.0),
+                (t - 2.0).clamp(0.0, 1.0),
+            );
+            framebuffer.set(index % self.width, index / self.width, color);
+        }
+        framebuffer
+    }
+}
+
+fn not_negative(value: f32) -> f32 {
+    if value < 0.0 {
+        0.0
+    } else {
+        value
+    }
+}
+
+// The film pixels a tile renders into, grown by the filter margin on every
+// side and clipped to the film. The tile's own pixels continue from the
+// film, the ones around it start empty and only collect the samples that
+// spill over.
+// Samples each active pixel gets in a pass, if it comes before the pixel at
+// index end of the film.
+#[derive(Clone, Copy, Debug)]
+pub struct Pass {
+    pub count: u32,
+    end: usize,
+}
+
+struct TileFilm {
+    tile: Tile,
+    area: Tile,
+    pixels: Vec<FilmPixel>,
+}
+
+impl TileFilm {
+    fn index(&self, x: u32, y: u32) -> usize {
+        ((y - self.area.y) * self.area.width + x - self.area.x) as usize
+    }
+
+    // Adds a sample taken at offset from the center of pixel x, y, with y
+    // pointing up like the frame, to every pixel the filter reaches.
+    fn splat(&mut self, filter: &Filter, x: u32, y: u32, color: Vec3, offset: (f32, f32)) {
+        let margin = filter.margin() as i32;
+        for b in -margin..=margin {
+            for a in -margin..=margin {
+                let px = x as i32 + a;
+                let py = y as i32 + b;
+                if !self.area.contains_signed(px, py) {
+                    continue;
+                }
+                // Rows count down the film, so the pixel b rows below is b
+                // further from the sample along the frame's y.
+                let weight = filter.evaluate(offset.0 - a as f32, offset.1 + b as f32);
+                if weight == 0.0 {
+                    continue;
+                }
+                let index = self.index(px as u32, py as u32);
+                let pixel = &mut self.pixels[index];
+                pixel.weighted = pixel.weighted + color * weight;
+                pixel.weight += weight;
+            }
+        }
+    }
+}
+
+pub struct Renderer<'a> {
+    camera: &'a Camera,
+    world: &'a Hitable,
+    lights: &'a LightList<'a>,
+    settings: RenderSettings,
+    tiles: Vec<Tile>,
+    nan_samples: AtomicU64,
+    infinite_samples: AtomicU64,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(
+        camera: &'a Camera,
+        world: &'a Hitable,
+        lights: &'a LightList<'a>,
+        settings: RenderSettings,
+    ) -> Renderer<'a> {
+        Renderer {
+            camera,
+            world,
+            lights,
+            settings,
+            tiles: tiles(&settings.region, settings.tile_size, settings.tile_order),
+            nan_samples: AtomicU64::new(0),
+            infinite_samples: AtomicU64::new(0),
+        }
+    }
+
+    pub fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    // Samples so far that came out NaN and infinite.
+    pub fn invalid_samples(&self) -> (u64, u64) {
+        (
+            self.nan_samples.load(AtomicOrdering::SeqCst),
+            self.infinite_samples.load(AtomicOrdering::SeqCst),
+        )
+    }
+
+    // Without adaptive sampling all samples go in a single pass, with it the
+    // pixels are revisited in rounds of the minimum sample count.
+    pub fn render(&self) -> Film {
+        let pass_samples = match self.settings.adaptive {
+            Some(adaptive) => adaptive.min_samples.max(1),
+            None => self.settings.samples,
+        };
+        let mut film = Film::new(&self.settings.region);
+        while let Some(pass) = self.next_pass(&film, pass_samples) {
+            self.render_pass(&mut film, pass);
+        }
+        film
+    }
+
+    // The number of samples the whole film may use, samples per pixel on
+    // average.
+    pub fn budget(&self, film: &Film) -> u64 {
+        u64::from(self.settings.samples) * film.pixels.len() as u64
+    }
+
+    pub fn active_pixels(&self, film: &Film) -> usize {
+        film.pixels
+            .iter()
+            .filter(|pixel| self.is_active(pixel))
+            .count()
+    }
+
+    // The next pass over the pixels that are still active, None once the
+    // render is done. Passes shrink towards the end of an adaptive render so
+    // the few pixels left don't overshoot the budget, and once there are
+    // fewer samples left than active pixels the last one only reaches as many
+    // of them as there are samples.
+    pub fn next_pass(&self, film: &Film, pass_samples: u32) -> Option<Pass> {
+        let active = self.active_pixels(film) as u64;
+        let remaining = self.budget(film).saturating_sub(film.spent());
+        if active == 0 || remaining == 0 {
+            return None;
+        }
+        if remaining >= active {
+            return Some(Pass {
+                count: u64::from(pass_samples).min(remaining / active) as u32,
+                end: film.pixels.len(),
+            });
+        }
+        let end = film
+            .pixels
+            .iter()
+            .enumerate()
+            .filter(|&(_, pixel)| self.is_active(pixel))
+            .nth(remaining as usize)
+            .map_or(film.pixels.len(), |(index, _)| index);
+        Some(Pass { count: 1, end })
+    }
+
+    // Pixels stop at the maximum sample count, and with adaptive sampling
+    // also once they have the minimum and their error is below the threshold.
+    fn is_active(&self, pixel: &FilmPixel) -> bool {
+        match self.settings.adaptive {
+            Some(adaptive) => {
+                pixel.samples < adaptive.max_samples
+                    && (pixel.samples < adaptive.min_samples || pixel.error() > adaptive.threshold)
+            }
+            None => pixel.samples < self.settings.samples,
+        }
+    }
+
+    // Adds count samples to every active pixel. Every thread takes the next
+    // tile in order until none are left. Each pixel's samples are summed in
+    // order and continue the sum of earlier passes, so the image comes out
+    // the same however it's split into passes and whichever threads trace it.
+    // Samples that a wide filter spreads past their tile are added once the
+    // pass is done, tile by tile from the top left, so they don't depend on
+    // the threads either. Other tiles or passes only change their rounding.
+    pub fn render_pass(&self, film: &mut Film, pass: Pass) {
+        let margin = self.settings.filter.margin();
+        let next = AtomicUsize::new(0);
+        let spilled = Mutex::new(Vec::new());
+        {
+            let film = Mutex::new(&mut *film);
+            rayon::scope(|scope| {
+                for _ in 0..rayon::current_num_threads() {
+                    scope.spawn(|_| {
+                        while let Some(tile) =
+                            self.tiles.get(next.fetch_add(1, AtomicOrdering::SeqCst))
+                        {
+                            let mut tile_film = film.lock().unwrap().read_tile(tile, margin);
+                            self.render_tile(&mut tile_film, pass);
+                            film.lock().unwrap().write_tile(&tile_film);
+                            if margin > 0 {
+                                spilled.lock().unwrap().push(tile_film);
+                            }
+                        }
+                    });
+                }
+            });
+        }
+        let mut spilled = spilled.into_inner().unwrap();
+        spilled.sort_by_key(|tile_film| (tile_film.tile.y, tile_film.tile.x));
+        for tile_film in &spilled {
+            film.add_spill(tile_film);
+        }
+    }
+
+    fn render_tile(&self, film: &mut TileFilm, pass: Pass) {
+        let max_samples = self.settings.max_samples();
+        let tile = film.tile;
+        for y in tile.y..tile.y + tile.height {
+            for x in tile.x..tile.x + tile.width {
+                let index = film.index(x, y);
+                let start = film.pixels[index].samples;
+                if !self.is_active(&film.pixels[index])
+                    || (y * self.settings.region.width + x) as usize >= pass.end
+                {
+                    continue;
+                }
+                let end = (start + pass.count).min(max_samples);
+                for s in start..end {
+                    let (color, offset) = self.sample(x, y, s);
+                    let color = self.check_sample(x, y, s, color);
+                    let pixel = &mut film.pixels[index];
+                    pixel.sum = pixel.sum + color;
+                    pixel.squares += luminance(&color) * luminance(&color);
+                    film.splat(&self.settings.filter, x, y, color, offset);
+                }
+                film.pixels[index].samples = end;
+            }
+        }
+    }
+
+    // Counts samples that came out NaN or infinite and replaces them with
+    // black, so a single broken path doesn't ruin its pixel for good.
+    fn check_sample(&self, x: u32, y: u32, s: u32, color: Vec3) -> Vec3 {
+        let channels = [color.r(), color.g(), color.b()];
+        let (counter, kind) = if channels.iter().any(|channel| channel.is_nan()) {
+            (&self.nan_samples, "NaN")
+        } else if channels.iter().any(|channel| channel.is_infinite()) {
+            (&self.infinite_samples, "infinite")
+        } else {
+            return color;
+        };
+        counter.fetch_add(1, AtomicOrdering::SeqCst);
+        if self.settings.debug_invalid {
+            eprintln!(
+                "{} sample {} in pixel {},{}",
+                kind,
+                s,
+                self.settings.region.x + x,
+                self.settings.region.y + y
+            );
+        }
+        Vec3(0.0, 0.0, 0.0)
+    }
+
+    // One sample of the film pixel at x, y, counted from the top left corner
+    // of the region, and where it was taken relative to the pixel's center.
+    fn sample(&self, x: u32, y: u32, s: u32) -> (Vec3, (f32, f32)) {
+        let settings = &self.settings;
+        let i = settings.region.x + x;
+        let j = settings.height - 1 - (settings.region.y + y);
+        let pixel = u64::from(j * settings.width + i);
+        let mut sampler = Sampler::new(
+            settings.sampler,
+            settings.seed,
+            settings.max_samples(),
+            pixel,
+            s,
+        );
+        let (du, dv) = sampler.next_2d();
+        let u = (i as f32 + du) / settings.width as f32;
+        let v = (j as f32 + dv) / settings.height as f32;
+        let ray = self.camera.get_ray(u, v, &mut sampler);
+        let color = settings.integrator.color(
+            &ray,
+            self.world,
+            self.lights,
+            &PathLimits {
+                max_depth: settings.max_depth,
+                roulette: settings.roulette,
+                indirect_clamp: settings.indirect_clamp,
+            },
+            &mut sampler,
+        );
+        (color, (du - 0.5, dv - 0.5))
+    }
+}
+
+// Renders in passes of pass_samples samples per pixel and hands the film to
+// snapshot every snapshot_passes passes, or once snapshot_seconds have gone
+// by since the last one. Zero turns either trigger off. The film may already
+// hold samples from a checkpoint, rendering carries on after them.
+#[derive(Clone, Copy, Debug)]
+pub struct Progressive {
+    pub pass_samples: u32,
+    pub snapshot_passes: u32,
+    pub snapshot_seconds: f64,
+}
+
+impl Progressive {
+    pub fn render<F: FnMut(&Film)>(
+        &self,
+        renderer: &Renderer,
+        film: Film,
+        mut snapshot: F,
+    ) -> Film {
+        let mut film = film;
+        let budget = renderer.budget(&film);
+        let resumed = film.spent();
+        let start = Instant::now();
+        let mut last_snapshot = start;
+        let mut pass = 0;
+        while let Some(next) = renderer.next_pass(&film, self.pass_samples.max(1)) {
+            renderer.render_pass(&mut film, next);
+            pass += 1;
+            let spent = film.spent();
+            let elapsed = start.elapsed().as_secs_f64();
+            let remaining =
+                elapsed / (spent - resumed) as f64 * budget.saturating_sub(spent) as f64;
+            let active = renderer.active_pixels(&film);
+            println!(
+                "Pass {}, {:.1}/{} spp, {} pixels left, {} elapsed, ETA {}",
+                pass,
+                film.mean_samples(),
+                renderer.settings().samples,
+                active,
+                format_seconds(elapsed),
+                format_seconds(remaining)
+            );
+            if active == 0 || spent >= budget {
+                break;
+            }
+            let due_by_passes = self.snapshot_passes > 0 && pass % self.snapshot_passes == 0;
+            let due_by_time = self.snapshot_seconds > 0.0
+                && last_snapshot.elapsed().as_secs_f64() >= self.snapshot_seconds;
+            if due_by_passes || due_by_time {
+                snapshot(&film);
+                last_snapshot = Instant::now();
+            }
+        }
+        film
+    }
+}
+
+fn format_seconds(seconds: f64) -> String {
+    let whole = seconds.round() as u64;
+    if whole >= 3600 {
+        format!("{}h{:02}m{:02}s", whole / 3600, whole / 60 % 60, whole % 60)
+    } else if whole >= 60 {
+        format!("{}m{:02}s", whole / 60, whole % 60)
+    } else {
+        format!("{:.1}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayon::ThreadPoolBuilder;
+    use utils::cornell_box;
+
+    fn render(threads: usize, settings: RenderSettings) -> Vec<[u32; 3]> {
+        let (camera, hitables) = cornell_box(settings.width, settings.height);
+        let world = BvhBuilder::Flat.build(hitables, 0.0, 1.0);
+        let lights = LightList::new(&world);
+        let renderer = Renderer::new(&camera, &world, &lights, settings);
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        let framebuffer = pool.install(|| renderer.render().to_framebuffer());
+        framebuffer
+            .pixels()
+            .iter()
+            .map(|p| [p.r().to_bits(), p.g().to_bits(), p.b().to_bits()])
+            .collect()
+    }
+
+    fn settings(sampler: SamplerKind, filter: FilterKind) -> RenderSettings {
+        RenderSettings {
+            width: 12,
+            height: 12,
+            samples: 4,
+            max_depth: 8,
+            roulette: Roulette {
+                depth: 3,
+                min_survival: 0.05,
+            },
+            integrator: Integrator::Mis,
+            sampler,
+            seed: 5,
+            region: Region::full(12, 12),
+            tile_size: 4,
+            tile_order: TileOrder::Spiral,
+            adaptive: None,
+            filter: Filter {
+                kind: filter,
+                radius: filter.default_radius(),
+            },
+            indirect_clamp: None,
+            debug_invalid: false,
+        }
+    }
+
+    #[test]
+    fn output_does_not_depend_on_threads() {
+        for &sampler in &SAMPLER_KINDS {
+            for &filter in &[FilterKind::Box, FilterKind::Mitchell] {
+                let settings = settings(sampler, filter);
+                let one = render(1, settings);
+                assert!(one.iter().any(|pixel| pixel[0] != 0));
+                assert!(one == render(3, settings), "{} {}", sampler.name(), filter.name());
+            }
+        }
+    }
+
+    #[test]
+    fn adaptive_output_does_not_depend_on_threads() {
+        let settings = RenderSettings {
+            samples: 8,
+            adaptive: Some(Adaptive {
+                min_samples: 2,
+                max_samples: 32,
+                threshold: 0.05,
+            }),
+            ..settings(SamplerKind::Sobol, FilterKind::Box)
+        };
+        assert!(render(1, settings) == render(4, settings));
+    }
+}
@@ -0,0 +1,337 @@
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+// Streams keep generators made from the same global seed apart.
+pub const SCENE_STREAM: u64 = 0;
+pub const BVH_STREAM: u64 = 1;
+const PIXEL_STREAM: u64 = 2;
+const PATTERN_STREAM: u64 = 3;
+
+// Dimensions are handed out in a fixed layout so every sample of a pixel uses
+// the same dimension for the same decision: the pixel position, the lens and
+// the shutter time first, then a block per bounce with a slot for each step.
+// A bounce that skips a step leaves its slot unused instead of shifting the
+// later ones.
+const CAMERA_DIMENSIONS: u32 = 5;
+const BOUNCE_DIMENSIONS: u32 = 7;
+pub const LIGHT_SLOT: u32 = 0;
+pub const BSDF_SLOT: u32 = 3;
+pub const ROULETTE_SLOT: u32 = 6;
+
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerKind {
+    Random,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+pub const SAMPLER_KINDS: [SamplerKind; 4] = [
+    SamplerKind::Random,
+    SamplerKind::Stratified,
+    SamplerKind::Halton,
+    SamplerKind::Sobol,
+];
+
+impl SamplerKind {
+    pub fn from_name(name: &str) -> Option<SamplerKind> {
+        match name {
+            "random" => Some(SamplerKind::Random),
+            "stratified" => Some(SamplerKind::Stratified),
+            "halton" => Some(SamplerKind::Halton),
+            "sobol" => Some(SamplerKind::Sobol),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SamplerKind::Random => "random",
+            SamplerKind::Stratified => "stratified",
+            SamplerKind::Halton => "halton",
+            SamplerKind::Sobol => "sobol",
+        }
+    }
+}
+
+// Random numbers for one sample of one pixel. Every sample is derived from
+// the global seed and its position alone, so the image doesn't depend on
+// which thread traces what or in which order.
+//
+// random: independent uniform numbers.
+// stratified: correlated multi-jittered samples over the pixel's samples,
+//   with the strata shuffled differently in every dimension.
+// halton: the Halton sequence with a prime base per dimension, randomly
+//   shifted per pixel. Past the prime table it falls back to random.
+// sobol: pairs of Owen scrambled Sobol dimensions, with the sample order
+//   shuffled per pair so that pairs don't correlate.
+pub struct Sampler {
+    kind: SamplerKind,
+    samples: u32,
+    sample: u32,
+    dimension: u32,
+    pattern: u64,
+    rng: XorShiftRng,
+}
+
+impl Sampler {
+    pub fn new(kind: SamplerKind, seed: u64, samples: u32, pixel: u64, sample: u32) -> Sampler {
+        Sampler {
+            kind,
+            samples,
+            sample,
+            dimension: 0,
+            pattern: hash(&[seed, PATTERN_STREAM, pixel]),
+            rng: seeded_rng(&[seed, PIXEL_STREAM, pixel, u64::from(sample)]),
+        }
+    }
+
+    // Moves to the dimensions reserved for one step of a bounce.
+    pub fn start_bounce(&mut self, depth: u32, slot: u32) {
+        self.dimension = CAMERA_DIMENSIONS + depth * BOUNCE_DIMENSIONS + slot;
+    }
+
+    pub fn next_1d(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        let key = self.dimension_key(dimension);
+        match self.kind {
+            SamplerKind::Random => self.rng.gen(),
+            SamplerKind::Stratified => {
+                let stratum = permute(self.sample % self.samples, self.samples, key);
+                (stratum as f32 + self.rng.gen::<f32>()) / self.samples as f32
+            }
+            SamplerKind::Halton => self.halton(dimension),
+            SamplerKind::Sobol => {
+                let index = nested_uniform_scramble(self.sample, key);
+                to_unit(nested_uniform_scramble(index.reverse_bits(), key ^ 0xa511_e9b3))
+            }
+        }
+    }
+
+    pub fn next_2d(&mut self) -> (f32, f32) {
+        let dimension = self.dimension;
+        self.dimension += 2;
+        let key = self.dimension_key(dimension);
+        match self.kind {
+            SamplerKind::Random => (self.rng.gen(), self.rng.gen()),
+            SamplerKind::Stratified => self.correlated_multi_jitter(key),
+            SamplerKind::Halton => (self.halton(dimension), self.halton(dimension + 1)),
+            SamplerKind::Sobol => {
+                let index = nested_uniform_scramble(self.sample, key);
+                (
+                    to_unit(nested_uniform_scramble(index.reverse_bits(), key ^ 0xa511_e9b3)),
+                    to_unit(nested_uniform_scramble(sobol_second(index), key ^ 0x63d8_3595)),
+                )
+            }
+        }
+    }
+
+    fn dimension_key(&self, dimension: u32) -> u32 {
+        hash(&[self.pattern, u64::from(dimension)]) as u32
+    }
+
+    // Kensler, "Correlated Multi-Jittered Sampling": jittered in a grid of
+    // m by n cells and stratified along both axes, for any sample count.
+    fn correlated_multi_jitter(&mut self, key: u32) -> (f32, f32) {
+        let count = self.samples;
+        let m = ((count as f32).sqrt() as u32).max(1);
+        let n = count.div_ceil(m);
+        let s = permute(self.sample % count, count, key.wrapping_mul(0x5163_3e2d));
+        let sx = permute(s % m, m, key.wrapping_mul(0x68bc_21eb));
+        let sy = permute(s / m, n, key.wrapping_mul(0x02e5_be93));
+        let jx = self.rng.gen::<f32>();
+        let jy = self.rng.gen::<f32>();
+        (
+            ((sx as f32 + (sy as f32 + jx) / n as f32) / m as f32).min(ONE_MINUS_EPSILON),
+            ((s as f32 + jy) / count as f32).min(ONE_MINUS_EPSILON),
+        )
+    }
+
+    fn halton(&mut self, dimension: u32) -> f32 {
+        match PRIMES.get(dimension as usize) {
+            Some(&base) => {
+                let shift = hash_to_unit(&[self.pattern, u64::from(dimension)]);
+                let value = radical_inverse(base, self.sample) + shift;
+                (value - value.floor()).min(ONE_MINUS_EPSILON)
+            }
+            None => self.rng.gen(),
+        }
+    }
+}
+
+// Draws that don't fit the dimension layout, such as rejection sampling, come
+// from the sample's own generator.
+impl Rng for Sampler {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+}
+
+// Generator for work outside the per pixel render, like building scenes.
+pub fn seeded_rng(key: &[u64]) -> XorShiftRng {
+    let mut state = hash(key);
+    let a = splitmix64(&mut state);
+    let b = splitmix64(&mut state);
+    // Xorshift is stuck at zero, keep one bit set.
+    XorShiftRng::from_seed([
+        a as u32 | 1,
+        (a >> 32) as u32,
+        b as u32,
+        (b >> 32) as u32,
+    ])
+}
+
+// Uniform number in [0, 1) that only depends on the key, for places that
+// need randomness but have no sampler at hand.
+pub fn hash_to_unit(key: &[u64]) -> f32 {
+    (hash(key) >> 40) as f32 / (1u64 << 24) as f32
+}
+
+fn hash(key: &[u64]) -> u64 {
+    let mut h = 0;
+    for &value in key {
+        let mut state = h ^ value;
+        h = splitmix64(&mut state);
+    }
+    h
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn to_unit(bits: u32) -> f32 {
+    (bits >> 8) as f32 / (1u32 << 24) as f32
+}
+
+// Kensler's hashed permutation of 0..len, picked by key.
+fn permute(index: u32, len: u32, key: u32) -> u32 {
+    let mut w = len - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    let mut i = index;
+    loop {
+        i ^= key;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= key >> 16;
+        i ^= (i & w) >> 4;
+        i ^= key >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= key >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | key >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < len {
+            return i.wrapping_add(key) % len;
+        }
+    }
+}
+
+// Second Sobol dimension, the first is the bit reversed index.
+fn sobol_second(index: u32) -> u32 {
+    let mut index = index;
+    let mut direction = 1u32 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+    result
+}
+
+// Burley, "Practical Hash-based Owen Scrambling". Every bit is flipped
+// depending on the bits above it, which keeps the stratification of Sobol
+// points while randomizing them.
+fn nested_uniform_scramble(x: u32, key: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    x = x.wrapping_add(key);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x.reverse_bits()
+}
+
+fn radical_inverse(base: u32, index: u32) -> f32 {
+    let inverse_base = 1.0 / f64::from(base);
+    let mut index = index;
+    let mut reversed = 0.0;
+    let mut scale = inverse_base;
+    while index > 0 {
+        reversed += f64::from(index % base) * scale;
+        index /= base;
+        scale *= inverse_base;
+    }
+    reversed as f32
+}
+
+// Bases for the first 64 Halton dimensions, enough for eight bounces.
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_sample_gives_the_same_numbers() {
+        for &kind in &SAMPLER_KINDS {
+            let mut a = Sampler::new(kind, 7, 16, 42, 3);
+            let mut b = Sampler::new(kind, 7, 16, 42, 3);
+            for depth in 0..3 {
+                a.start_bounce(depth, BSDF_SLOT);
+                b.start_bounce(depth, BSDF_SLOT);
+                assert_eq!(a.next_2d(), b.next_2d(), "{}", kind.name());
+                assert_eq!(a.next_1d(), b.next_1d(), "{}", kind.name());
+            }
+        }
+    }
+
+    // Random and stratified draw from a stream as well, so only the low
+    // discrepancy samplers keep every value when a step is skipped.
+    #[test]
+    fn skipped_steps_leave_later_dimensions_alone() {
+        for &kind in &[SamplerKind::Halton, SamplerKind::Sobol] {
+            let mut full = Sampler::new(kind, 7, 16, 42, 3);
+            let mut skipping = Sampler::new(kind, 7, 16, 42, 3);
+            assert_eq!(full.next_2d(), skipping.next_2d());
+            full.next_2d();
+            full.next_1d();
+            for depth in 0..2 {
+                full.start_bounce(depth, LIGHT_SLOT);
+                full.next_2d();
+                full.next_1d();
+                full.start_bounce(depth, BSDF_SLOT);
+                skipping.start_bounce(depth, BSDF_SLOT);
+                assert_eq!(full.next_2d(), skipping.next_2d(), "{}", kind.name());
+                full.start_bounce(depth, ROULETTE_SLOT);
+                skipping.start_bounce(depth, ROULETTE_SLOT);
+                assert_eq!(full.next_1d(), skipping.next_1d(), "{}", kind.name());
+            }
+        }
+    }
+}
@@ -0,0 +1,677 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::SplitWhitespace;
+
+use vector::Vec3;
+use hitable::*;
+use material::*;
+use sphere::*;
+use texture::*;
+use camera::Camera;
+use utils::*;
+use loader::load_mesh;
+use sampler::*;
+use rand::XorShiftRng;
+
+// Scene files are line based. Blank lines and everything after `#` are
+// ignored, every other line is a single statement:
+//
+//   camera lookfrom 278 278 -800 lookat 278 278 0 vfov 40
+//   texture <name> constant <r> <g> <b>
+//   texture <name> checker <odd texture> <even texture>
+//   texture <name> noise <scale>
+//   texture <name> image <path>
+//   texture <name> vertex_color
+//   material <name> lambertian <texture>
+//   material <name> metal <r> <g> <b> <fuzz>
+//   material <name> conductor <metal> <u roughness> <v roughness>
+//   material <name> dielectric <refraction index> [roughness <r>]
+//                                 [absorption <r> <g> <b>]
+//   material <name> principled [<parameter> <texture or number>]...
+//   material <name> diffuse_light <texture>
+//   material <name> isotropic <texture>
+//   material <name> normal_map <material> <texture>
+//   material <name> bump_map <material> <texture> <scale>
+//   object <name> <shape> ...
+//   object <name> mesh <path> [material]
+//   add <object>
+//
+// A conductor's metal is gold, copper, silver, aluminium or
+// `custom <eta r g b> <k r g b>`, its roughness follows the surface's u and
+// v directions. A dielectric is smooth and clear unless given a roughness,
+// or an absorption coefficient per unit of distance travelled inside it.
+//
+// Principled parameters may come in any order and unset ones keep their
+// defaults: base_color (0.8), metallic (0), roughness (0.5), specular (0.5),
+// sheen (0), clearcoat (0) and transmission (0). A number is a constant gray
+// texture, and every parameter but base_color averages its texture's
+// channels to a value in [0, 1], as in
+//
+//   material paint principled base_color red metallic 0.2 clearcoat 1
+//
+// Objects are only rendered once they are added to the world, so wrappers
+// such as `translate` or `rotate_y` can refer to objects defined earlier.
+// See scenes/cornell_box.scene for a complete example.
+
+#[derive(Debug)]
+pub struct SceneError {
+    pub path: PathBuf,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.path.display(), self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.path.display(), self.line, self.message)
+        }
+    }
+}
+
+impl Error for SceneError {}
+
+pub fn load_scene(
+    path: &Path,
+    nx: u32,
+    ny: u32,
+    seed: u64,
+) -> Result<(Camera, Vec<Hitable>), SceneError> {
+    let mut source = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut source))
+        .map_err(|err| SceneError {
+            path: path.to_path_buf(),
+            line: 0,
+            message: err.to_string(),
+        })?;
+    parse_scene(&source, path, nx, ny, seed)
+}
+
+// The scene source read from path, which meshes are relative to.
+fn parse_scene(
+    source: &str,
+    path: &Path,
+    nx: u32,
+    ny: u32,
+    seed: u64,
+) -> Result<(Camera, Vec<Hitable>), SceneError> {
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut parser = SceneParser::new(base_dir, seed);
+    for (index, line) in source.lines().enumerate() {
+        parser.parse_line(line).map_err(|message| SceneError {
+            path: path.to_path_buf(),
+            line: index + 1,
+            message,
+        })?;
+    }
+    parser.finish(nx, ny).map_err(|message| SceneError {
+        path: path.to_path_buf(),
+        line: 0,
+        message,
+    })
+}
+
+struct CameraSettings {
+    lookfrom: Vec3,
+    lookat: Vec3,
+    vup: Vec3,
+    vfov: f32,
+    aperture: f32,
+    focus_dist: f32,
+    time0: f32,
+    time1: f32,
+}
+
+impl CameraSettings {
+    fn new() -> CameraSettings {
+        CameraSettings {
+            lookfrom: Vec3(0.0, 0.0, 0.0),
+            lookat: Vec3(0.0, 0.0, -1.0),
+            vup: Vec3(0.0, 1.0, 0.0),
+            vfov: 40.0,
+            aperture: 0.0,
+            focus_dist: 10.0,
+            time0: 0.0,
+            time1: 1.0,
+        }
+    }
+
+    fn build(&self, nx: u32, ny: u32) -> Camera {
+        Camera::new(
+            self.lookfrom,
+            self.lookat,
+            self.vup,
+            self.vfov,
+            nx as f32 / ny as f32,
+            self.aperture,
+            self.focus_dist,
+            self.time0,
+            self.time1,
+        )
+    }
+}
+
+struct Tokens<'a> {
+    iter: SplitWhitespace<'a>,
+}
+
+impl<'a> Tokens<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        self.iter.next()
+    }
+
+    fn word(&mut self, what: &str) -> Result<&'a str, String> {
+        self.iter.next().ok_or_else(|| format!("expected {}", what))
+    }
+
+    fn float(&mut self, what: &str) -> Result<f32, String> {
+        let token = self.word(what)?;
+        token
+            .parse::<f32>()
+            .map_err(|_| format!("expected {}, found `{}`", what, token))
+    }
+
+    fn vec3(&mut self, what: &str) -> Result<Vec3, String> {
+        Ok(Vec3(
+            self.float(what)?,
+            self.float(what)?,
+            self.float(what)?,
+        ))
+    }
+
+    fn end(&mut self) -> Result<(), String> {
+        match self.iter.next() {
+            Some(token) => Err(format!("unexpected `{}`", token)),
+            None => Ok(()),
+        }
+    }
+}
+
+struct SceneParser<'a> {
+    base_dir: &'a Path,
+    camera: Option<CameraSettings>,
+    textures: HashMap<String, Texture>,
+    materials: HashMap<String, Material>,
+    objects: HashMap<String, Hitable>,
+    world: Vec<Hitable>,
+    // Noise textures are generated while parsing, in file order.
+    rng: RefCell<XorShiftRng>,
+}
+
+impl<'a> SceneParser<'a> {
+    fn new(base_dir: &'a Path, seed: u64) -> SceneParser<'a> {
+        SceneParser {
+            base_dir,
+            camera: None,
+            textures: HashMap::new(),
+            materials: HashMap::new(),
+            objects: HashMap::new(),
+            world: Vec::new(),
+            rng: RefCell::new(seeded_rng(&[seed, SCENE_STREAM])),
+        }
+    }
+
+    fn finish(self, nx: u32, ny: u32) -> Result<(Camera, Vec<Hitable>), String> {
+        let camera = match self.camera {
+            Some(ref settings) => settings.build(nx, ny),
+            None => return Err("scene has no camera".to_string()),
+        };
+        if self.world.is_empty() {
+            return Err("scene has no objects, use `add` to put objects in the world".to_string());
+        }
+        Ok((camera, self.world))
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let line = match line.find('#') {
+            Some(index) => &line[..index],
+            None => line,
+        };
+        let mut tokens = Tokens {
+            iter: line.split_whitespace(),
+        };
+        match tokens.next() {
+            None => Ok(()),
+            Some("camera") => self.parse_camera(&mut tokens),
+            Some("texture") => {
+                let name = tokens.word("texture name")?;
+                let texture = self.parse_texture(&mut tokens)?;
+                tokens.end()?;
+                insert_unique(&mut self.textures, "texture", name, texture)
+            }
+            Some("material") => {
+                let name = tokens.word("material name")?;
+                let material = self.parse_material(&mut tokens)?;
+                tokens.end()?;
+                insert_unique(&mut self.materials, "material", name, material)
+            }
+            Some("object") => {
+                let name = tokens.word("object name")?;
+                let object = self.parse_object(&mut tokens)?;
+                tokens.end()?;
+                insert_unique(&mut self.objects, "object", name, object)
+            }
+            Some("add") => {
+                let object = self.object(&mut tokens)?;
+                tokens.end()?;
+                self.world.push(object);
+                Ok(())
+            }
+            Some(keyword) => Err(format!("unknown statement `{}`", keyword)),
+        }
+    }
+
+    fn parse_camera(&mut self, tokens: &mut Tokens) -> Result<(), String> {
+        if self.camera.is_some() {
+            return Err("camera is already defined".to_string());
+        }
+        let mut settings = CameraSettings::new();
+        while let Some(key) = tokens.next() {
+            match key {
+                "lookfrom" => settings.lookfrom = tokens.vec3("lookfrom position")?,
+                "lookat" => settings.lookat = tokens.vec3("lookat position")?,
+                "vup" => settings.vup = tokens.vec3("up vector")?,
+                "vfov" => settings.vfov = tokens.float("vertical field of view")?,
+                "aperture" => settings.aperture = tokens.float("aperture")?,
+                "focus_dist" => settings.focus_dist = tokens.float("focus distance")?,
+                "time" => {
+                    settings.time0 = tokens.float("shutter open time")?;
+                    settings.time1 = tokens.float("shutter close time")?;
+                }
+                _ => return Err(format!("unknown camera setting `{}`", key)),
+            }
+        }
+        self.camera = Some(settings);
+        Ok(())
+    }
+
+    fn parse_texture(&self, tokens: &mut Tokens) -> Result<Texture, String> {
+        match tokens.word("texture kind")? {
+            "constant" => Ok(new_constant_texture(tokens.vec3("color")?)),
+            "checker" => {
+                let odd = self.texture(tokens)?;
+                let even = self.texture(tokens)?;
+                Ok(Texture::CheckedTexture(CheckerTexture::new(odd, even)))
+            }
+            "noise" => Ok(Texture::NoiseTexture(NoiseTexture::new(
+                tokens.float("noise scale")?,
+                &mut *self.rng.borrow_mut(),
+            ))),
+            "image" => {
+                let path = self.base_dir.join(tokens.word("image path")?);
+                Ok(Texture::ImageTexture(ImageTexture::load(&path)?))
+            }
+            "vertex_color" => Ok(Texture::VertexColor),
+            kind => Err(format!("unknown texture kind `{}`", kind)),
+        }
+    }
+
+    fn parse_material(&self, tokens: &mut Tokens) -> Result<Material, String> {
+        match tokens.word("material kind")? {
+            "lambertian" => Ok(new_labertian(self.texture(tokens)?)),
+            "metal" => {
+                let albedo = tokens.vec3("metal albedo")?;
+                let fuzz = tokens.float("metal fuzz")?;
+                Ok(Material::Metal(Metal::new(albedo, fuzz)))
+            }
+            "conductor" => {
+                let metal = tokens.word("metal")?;
+                let (eta, k) = match metal {
+                    "custom" => (
+                        tokens.vec3("index of refraction")?,
+                        tokens.vec3("extinction coefficient")?,
+                    ),
+                    name => MetalPreset::from_name(name)
+                        .ok_or_else(|| {
+                            format!(
+                                "unknown metal `{}`, expected gold, copper, silver, aluminium or \
+                                 custom",
+                                name
+                            )
+                        })?
+                        .eta_k(),
+                };
+                let roughness_x = tokens.float("roughness")?;
+                let roughness_y = tokens.float("roughness")?;
+                Ok(Material::Conductor(Conductor::new(eta, k, roughness_x, roughness_y)))
+            }
+            "dielectric" => {
+                let ref_idx = tokens.float("refraction index")?;
+                let mut roughness = 0.0;
+                let mut absorption = Vec3(0.0, 0.0, 0.0);
+                while let Some(key) = tokens.next() {
+                    match key {
+                        "roughness" => roughness = tokens.float("roughness")?,
+                        "absorption" => absorption = tokens.vec3("absorption")?,
+                        _ => return Err(format!("unknown dielectric setting `{}`", key)),
+                    }
+                }
+                Ok(Material::Dielectric(Dielectric::rough(ref_idx, roughness, absorption)))
+            }
+            "principled" => {
+                let mut principled = Principled::new(new_constant_texture(Vec3(0.8, 0.8, 0.8)));
+                while let Some(key) = tokens.next() {
+                    let parameter = match key {
+                        "base_color" => &mut principled.base_color,
+                        "metallic" => &mut principled.metallic,
+                        "roughness" => &mut principled.roughness,
+                        "specular" => &mut principled.specular,
+                        "sheen" => &mut principled.sheen,
+                        "clearcoat" => &mut principled.clearcoat,
+                        "transmission" => &mut principled.transmission,
+                        _ => return Err(format!("unknown principled parameter `{}`", key)),
+                    };
+                    *parameter = self.texture_or_number(tokens)?;
+                }
+                Ok(Material::Principled(principled))
+            }
+            "diffuse_light" => Ok(new_diffuce(self.texture(tokens)?)),
+            "isotropic" => Ok(Material::Isotropic(Isotropic::new(self.texture(tokens)?))),
+            "normal_map" => {
+                let material = self.material(tokens)?;
+                let map = SurfaceMap::Normal(self.texture(tokens)?);
+                Ok(Material::Mapped(Mapped::new(material, map)))
+            }
+            "bump_map" => {
+                let material = self.material(tokens)?;
+                let map = SurfaceMap::Bump(self.texture(tokens)?, tokens.float("bump scale")?);
+                Ok(Material::Mapped(Mapped::new(material, map)))
+            }
+            kind => Err(format!("unknown material kind `{}`", kind)),
+        }
+    }
+
+    fn parse_object(&self, tokens: &mut Tokens) -> Result<Hitable, String> {
+        match tokens.word("object kind")? {
+            "sphere" => {
+                let center = tokens.vec3("sphere center")?;
+                let radius = tokens.float("sphere radius")?;
+                Ok(new_sphere(center, radius, self.material(tokens)?))
+            }
+            "moving_sphere" => {
+                let center0 = tokens.vec3("sphere start center")?;
+                let center1 = tokens.vec3("sphere end center")?;
+                let time0 = tokens.float("start time")?;
+                let time1 = tokens.float("end time")?;
+                let radius = tokens.float("sphere radius")?;
+                Ok(Hitable::MovingSphere(MovingSphere::new(
+                    center0,
+                    center1,
+                    time0,
+                    time1,
+                    radius,
+                    self.material(tokens)?,
+                )))
+            }
+            "xy_rect" => {
+                let (a0, a1, b0, b1, k) = rect_bounds(tokens)?;
+                Ok(new_xyrect(a0, a1, b0, b1, k, self.material(tokens)?))
+            }
+            "xz_rect" => {
+                let (a0, a1, b0, b1, k) = rect_bounds(tokens)?;
+                Ok(new_xzrect(a0, a1, b0, b1, k, self.material(tokens)?))
+            }
+            "yz_rect" => {
+                let (a0, a1, b0, b1, k) = rect_bounds(tokens)?;
+                Ok(new_yzrect(a0, a1, b0, b1, k, self.material(tokens)?))
+            }
+            "box" => {
+                let p0 = tokens.vec3("box min corner")?;
+                let p1 = tokens.vec3("box max corner")?;
+                Ok(new_box_(p0, p1, self.material(tokens)?))
+            }
+            "triangle" => {
+                let v0 = tokens.vec3("triangle vertex")?;
+                let v1 = tokens.vec3("triangle vertex")?;
+                let v2 = tokens.vec3("triangle vertex")?;
+                Ok(new_triangle(v0, v1, v2, self.material(tokens)?))
+            }
+            "mesh" => {
+                let path = self.base_dir.join(tokens.word("mesh path")?);
+                let material = match tokens.next() {
+                    Some(name) => Some(self.lookup_material(name)?),
+                    None => None,
+                };
+                let default_material = material
+                    .clone()
+                    .unwrap_or_else(|| new_labertian(new_constant_texture(Vec3(0.73, 0.73, 0.73))));
+                let mut mesh = load_mesh(&path, default_material).map_err(|err| err.to_string())?;
+                match material {
+                    Some(material) => {
+                        mesh.materials = vec![material];
+                        mesh.face_materials.clear();
+                    }
+                    // Scanned meshes render with their captured colors unless
+                    // a material is given.
+                    None if !mesh.colors.is_empty() && mesh.face_materials.is_empty() => {
+                        mesh.materials = vec![new_labertian(Texture::VertexColor)];
+                    }
+                    None => {}
+                }
+                Ok(new_triangle_mesh(mesh))
+            }
+            "list" => {
+                let mut list = Vec::new();
+                while let Some(name) = tokens.next() {
+                    list.push(self.lookup_object(name)?);
+                }
+                if list.is_empty() {
+                    return Err("expected at least one object in list".to_string());
+                }
+                Ok(Hitable::HitableList(HitableList::new(list)))
+            }
+            "flip_normals" => Ok(new_flip_normals(self.object(tokens)?)),
+            "translate" => {
+                let object = self.object(tokens)?;
+                Ok(new_translate(object, tokens.vec3("translation")?))
+            }
+            "rotate_y" => {
+                let object = self.object(tokens)?;
+                Ok(new_rotate_y(object, tokens.float("rotation angle")?))
+            }
+            "constant_medium" => {
+                let boundary = self.object(tokens)?;
+                let density = tokens.float("medium density")?;
+                Ok(new_constant_medium(
+                    boundary,
+                    density,
+                    self.texture(tokens)?,
+                ))
+            }
+            kind => Err(format!("unknown object kind `{}`", kind)),
+        }
+    }
+
+    fn texture(&self, tokens: &mut Tokens) -> Result<Texture, String> {
+        let name = tokens.word("texture name")?;
+        self.textures
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("unknown texture `{}`", name))
+    }
+
+    // A texture name, or a number for a constant grey texture.
+    fn texture_or_number(&self, tokens: &mut Tokens) -> Result<Texture, String> {
+        let name = tokens.word("texture name or number")?;
+        if let Ok(value) = name.parse() {
+            return Ok(new_constant_texture(Vec3(value, value, value)));
+        }
+        self.textures
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("unknown texture `{}`", name))
+    }
+
+    fn material(&self, tokens: &mut Tokens) -> Result<Material, String> {
+        let name = tokens.word("material name")?;
+        self.lookup_material(name)
+    }
+
+    fn lookup_material(&self, name: &str) -> Result<Material, String> {
+        self.materials
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("unknown material `{}`", name))
+    }
+
+    fn object(&self, tokens: &mut Tokens) -> Result<Hitable, String> {
+        let name = tokens.word("object name")?;
+        self.lookup_object(name)
+    }
+
+    fn lookup_object(&self, name: &str) -> Result<Hitable, String> {
+        self.objects
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("unknown object `{}`", name))
+    }
+}
+
+fn rect_bounds(tokens: &mut Tokens) -> Result<(f32, f32, f32, f32, f32), String> {
+    Ok((
+        tokens.float("rect bound")?,
+        tokens.float("rect bound")?,
+        tokens.float("rect bound")?,
+        tokens.float("rect bound")?,
+        tokens.float("rect plane offset")?,
+    ))
+}
+
+fn insert_unique<T>(
+    map: &mut HashMap<String, T>,
+    what: &str,
+    name: &str,
+    value: T,
+) -> Result<(), String> {
+    if map.contains_key(name) {
+        return Err(format!("{} `{}` is already defined", what, name));
+    }
+    map.insert(name.to_string(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ray::Ray;
+
+    const HEADER: &str = "camera lookfrom 0 0 -5 lookat 0 0 0 vfov 40\n\
+                          texture grey constant 0.5 0.5 0.5\n\
+                          material matte lambertian grey\n";
+
+    fn parse(source: &str) -> Result<(Camera, Vec<Hitable>), SceneError> {
+        parse_scene(source, Path::new("test.scene"), 40, 20, 0)
+    }
+
+    fn error(source: &str) -> String {
+        match parse(source) {
+            Ok(_) => panic!("scene parsed"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_a_minimal_scene() {
+        let source = format!(
+            "{}\n# comments and blank lines are skipped\n\
+             object ball sphere 0 0 0 1 matte  # a unit sphere\n\
+             object wall xy_rect -2 2 -2 2 3 matte\n\
+             object moved translate ball 0 0 0\n\
+             object unused sphere 9 9 9 1 matte\n\
+             add moved\nadd wall\n",
+            HEADER
+        );
+        let (_, world) = parse(&source).unwrap();
+        assert_eq!(world.len(), 2);
+        assert!(matches!(world[0], Hitable::Translate(_)));
+        assert!(matches!(world[1], Hitable::XYRect(_)));
+
+        let ray = Ray::new(Vec3(0.0, 0.0, -5.0), Vec3(0.0, 0.0, 1.0), 0.0);
+        let rec = world[0].hit(&ray, 0.001, 100.0).unwrap();
+        assert!((rec.t - 4.0).abs() < 1e-5);
+        assert!(matches!(*rec.material, Material::Labertian(_)));
+        let rec = world[1].hit(&ray, 0.001, 100.0).unwrap();
+        assert!((rec.t - 8.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn a_scene_needs_a_camera_and_objects() {
+        assert_eq!(
+            error("object ball sphere 0 0 0 1 missing\n"),
+            "test.scene:1: unknown material `missing`"
+        );
+        assert_eq!(
+            error("texture t constant 1 1 1\n"),
+            "test.scene: scene has no camera"
+        );
+        assert_eq!(
+            error(HEADER),
+            "test.scene: scene has no objects, use `add` to put objects in the world"
+        );
+    }
+
+    #[test]
+    fn unknown_keywords_report_the_line() {
+        assert_eq!(
+            error(&format!("{}light sun 1 1 1\n", HEADER)),
+            "test.scene:4: unknown statement `light`"
+        );
+        assert_eq!(
+            error(&format!("{}object ball cube 0 0 0 1 matte\n", HEADER)),
+            "test.scene:4: unknown object kind `cube`"
+        );
+        assert_eq!(
+            error(&format!("{}\nmaterial gold plastic grey\n", HEADER)),
+            "test.scene:5: unknown material kind `plastic`"
+        );
+        assert_eq!(
+            error("camera lookfrom 0 0 0 zoom 2\n"),
+            "test.scene:1: unknown camera setting `zoom`"
+        );
+    }
+
+    #[test]
+    fn wrong_arity_reports_the_line() {
+        assert_eq!(
+            error(&format!("{}object ball sphere 0 0 0 1\n", HEADER)),
+            "test.scene:4: expected material name"
+        );
+        assert_eq!(
+            error(&format!("{}object ball sphere 0 0 0 1 matte matte\n", HEADER)),
+            "test.scene:4: unexpected `matte`"
+        );
+        assert_eq!(
+            error("texture t constant 1 1\n"),
+            "test.scene:1: expected color"
+        );
+        assert_eq!(
+            error(&format!("{}add\n", HEADER)),
+            "test.scene:4: expected object name"
+        );
+    }
+
+    #[test]
+    fn bad_numbers_report_the_line() {
+        assert_eq!(
+            error(&format!("{}object ball sphere 0 0 0 big matte\n", HEADER)),
+            "test.scene:4: expected sphere radius, found `big`"
+        );
+        assert_eq!(
+            error("camera lookfrom 0 0 0 vfov wide\n"),
+            "test.scene:1: expected vertical field of view, found `wide`"
+        );
+    }
+
+    #[test]
+    fn names_are_unique() {
+        let source = format!("{}material matte lambertian grey\n", HEADER);
+        assert_eq!(error(&source), "test.scene:4: material `matte` is already defined");
+    }
+}
@@ -1,8 +1,11 @@
+use std::f32;
+
 use ray::*;
 use hitable::*;
 use vector::*;
 use material::*;
 use utils::*;
+use light::*;
 
 #[derive(Clone, Debug)]
 pub struct Sphere {
@@ -33,29 +36,27 @@ impl Sphere {
             if temp < t_max && temp > t_min {
                 let t = temp;
                 let p = r.point_at_parameter(t);
-                let (u, v) = get_sphere_uv(&((p - self.center) / self.radius));
-                return Some(HitRecord::new(
-                    t,
-                    p,
-                    (p - self.center) / self.radius,
-                    &self.material,
-                    u,
-                    v,
-                ));
+                let normal = (p - self.center) / self.radius;
+                let (u, v) = get_sphere_uv(&normal);
+                let (dpdu, dpdv) = sphere_tangents(&normal, self.radius);
+                return Some(
+                    HitRecord::new(t, p, normal, &self.material, u, v)
+                        .with_tangents(dpdu, dpdv)
+                        .with_shape(shape_id(self)),
+                );
             }
             temp = (-b + (b * b - a * c).sqrt()) / a;
             if temp < t_max && temp > t_min {
                 let t = temp;
                 let p = r.point_at_parameter(t);
-                let (u, v) = get_sphere_uv(&((p - self.center) / self.radius));
-                return Some(HitRecord::new(
-                    t,
-                    p,
-                    (p - self.center) / self.radius,
-                    &self.material,
-                    u,
-                    v,
-                ));
+                let normal = (p - self.center) / self.radius;
+                let (u, v) = get_sphere_uv(&normal);
+                let (dpdu, dpdv) = sphere_tangents(&normal, self.radius);
+                return Some(
+                    HitRecord::new(t, p, normal, &self.material, u, v)
+                        .with_tangents(dpdu, dpdv)
+                        .with_shape(shape_id(self)),
+                );
             }
         }
         return None;
@@ -67,6 +68,33 @@ impl Sphere {
             self.center + Vec3(self.radius, self.radius, self.radius),
         ))
     }
+
+    pub fn collect_lights<'a>(&'a self, offset: Vec3, lights: &mut Vec<Light<'a>>) {
+        if self.material.is_light() {
+            lights.push(Light::Sphere(SphereLight::new(
+                self.center + offset,
+                self.radius,
+                &self.material,
+                shape_id(self),
+            )));
+        }
+    }
+}
+
+// Derivatives of the point at normal on a sphere of radius with respect to
+// the u and v of get_sphere_uv. dpdv vanishes at the poles.
+fn sphere_tangents(normal: &Vec3, radius: f32) -> (Vec3, Vec3) {
+    let dpdu = Vec3(normal.z(), 0.0, -normal.x()) * (2.0 * f32::consts::PI * radius);
+    let cos_theta = (normal.x() * normal.x() + normal.z() * normal.z()).sqrt();
+    if cos_theta == 0.0 {
+        return (dpdu, Vec3(0.0, 0.0, 0.0));
+    }
+    let dpdv = Vec3(
+        -normal.y() * normal.x() / cos_theta,
+        cos_theta,
+        -normal.y() * normal.z() / cos_theta,
+    ) * (f32::consts::PI * radius);
+    (dpdu, dpdv)
 }
 
 #[derive(Clone, Debug)]
@@ -116,27 +144,23 @@ impl MovingSphere {
             if temp < t_max && temp > t_min {
                 let t = temp;
                 let p = r.point_at_parameter(t);
-                return Some(HitRecord::new(
-                    t,
-                    p,
-                    (p - self.center(r.time())) / self.radius,
-                    &self.material,
-                    0.0,
-                    0.0,
-                ));
+                let normal = (p - self.center(r.time())) / self.radius;
+                let (dpdu, dpdv) = sphere_tangents(&normal, self.radius);
+                return Some(
+                    HitRecord::new(t, p, normal, &self.material, 0.0, 0.0)
+                        .with_tangents(dpdu, dpdv),
+                );
             }
             temp = (-b + (b * b - a * c).sqrt()) / a;
             if temp < t_max && temp > t_min {
                 let t = temp;
                 let p = r.point_at_parameter(t);
-                return Some(HitRecord::new(
-                    t,
-                    p,
-                    (p - self.center(r.time())) / self.radius,
-                    &self.material,
-                    0.0,
-                    0.0,
-                ));
+                let normal = (p - self.center(r.time())) / self.radius;
+                let (dpdu, dpdv) = sphere_tangents(&normal, self.radius);
+                return Some(
+                    HitRecord::new(t, p, normal, &self.material, 0.0, 0.0)
+                        .with_tangents(dpdu, dpdv),
+                );
             }
         }
         return None;
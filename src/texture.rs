@@ -1,5 +1,10 @@
+use std::path::Path;
+use stb_image::image;
+use rand::Rng;
+
 use vector::*;
 use perlin::*;
+use hitable::HitRecord;
 
 #[derive(Clone, Debug)]
 pub enum Texture {
@@ -7,6 +12,7 @@ pub enum Texture {
     CheckedTexture(CheckerTexture),
     NoiseTexture(NoiseTexture),
     ImageTexture(ImageTexture),
+    VertexColor,
 }
 
 impl Texture {
@@ -16,6 +22,15 @@ impl Texture {
             Texture::CheckedTexture(ref checked_texture) => checked_texture.value(u, v, p),
             Texture::NoiseTexture(ref noise_texture) => noise_texture.value(u, v, p),
             Texture::ImageTexture(ref image_texture) => image_texture.value(u, v, p),
+            Texture::VertexColor => Vec3(1.0, 1.0, 1.0),
+        }
+    }
+
+    // Vertex colors only exist at a hit, everything else just needs u, v and p.
+    pub fn value_at(&self, rec: &HitRecord) -> Vec3 {
+        match *self {
+            Texture::VertexColor => rec.vertex_color.unwrap_or(Vec3(1.0, 1.0, 1.0)),
+            _ => self.value(rec.u, rec.v, &rec.p),
         }
     }
 }
@@ -36,6 +51,20 @@ impl ImageTexture {
         }
     }
 
+    pub fn load(path: &Path) -> Result<ImageTexture, String> {
+        match image::load_with_depth(path, 3, true) {
+            image::LoadResult::ImageU8(image) => Ok(ImageTexture::new(
+                image.data,
+                image.width as u32,
+                image.height as u32,
+            )),
+            image::LoadResult::ImageF32(_) => {
+                Err(format!("{}: unsupported image format", path.display()))
+            }
+            image::LoadResult::Error(err) => Err(format!("{}: {}", path.display(), err)),
+        }
+    }
+
     pub fn value(&self, u: f32, v: f32, _p: &Vec3) -> Vec3 {
         let mut i: i32 = (u * self.nx as f32) as i32;
         let mut j: i32 = ((1.0 - v) * self.ny as f32 - 0.001) as i32;
@@ -101,14 +130,14 @@ impl CheckerTexture {
 
 #[derive(Clone, Debug)]
 pub struct NoiseTexture {
-    noise: Perlin,
+    noise: Box<Perlin>,
     scale: f32,
 }
 
 impl NoiseTexture {
-    pub fn new(scale: f32) -> NoiseTexture {
+    pub fn new<R: Rng>(scale: f32, rng: &mut R) -> NoiseTexture {
         NoiseTexture {
-            noise: Perlin::new(),
+            noise: Box::new(Perlin::new(rng)),
             scale: scale,
         }
     }
@@ -1,7 +1,7 @@
 use std::f32;
-use rand::{thread_rng, Rng};
+use rand::{Rng, XorShiftRng};
+use std::mem;
 use std::path::Path;
-use stb_image::image;
 
 use vector::Vec3;
 use hitable::*;
@@ -10,6 +10,7 @@ use sphere::*;
 use ray::*;
 use texture::*;
 use camera::Camera;
+use sampler::*;
 
 pub fn cornell_box(nx: u32, ny: u32) -> (Camera, Vec<Hitable>) {
     let mut list: Vec<Hitable> = Vec::new();
@@ -95,8 +96,8 @@ pub fn cornell_box(nx: u32, ny: u32) -> (Camera, Vec<Hitable>) {
     (cam, list)
 }
 
-pub fn simple_light() -> Vec<Hitable> {
-    let pertext = Texture::NoiseTexture(NoiseTexture::new(4.0));
+pub fn simple_light(nx: u32, ny: u32, rng: &mut XorShiftRng) -> (Camera, Vec<Hitable>) {
+    let pertext = Texture::NoiseTexture(NoiseTexture::new(4.0, rng));
     let mut list: Vec<Hitable> = Vec::new();
     list.push(new_sphere(
         Vec3(0.0, -1000.0, 0.0),
@@ -129,7 +130,22 @@ pub fn simple_light() -> Vec<Hitable> {
             4.0,
         )))),
     ));
-    list
+    let lookfrom = Vec3(25.0, 2.0, 3.0);
+    let lookat = Vec3(0.0, 0.0, 0.0);
+    let dist_to_focus = 10.0;
+    let aperture = 0.0;
+    let cam = Camera::new(
+        lookfrom,
+        lookat,
+        Vec3(0.0, 1.0, 0.0),
+        20.0,
+        nx as f32 / ny as f32,
+        aperture,
+        dist_to_focus,
+        0.0,
+        1.0,
+    );
+    (cam, list)
 }
 
 pub fn new_constant_medium(b: Hitable, d: f32, a: Texture) -> Hitable {
@@ -168,8 +184,15 @@ pub fn new_box_(p0: Vec3, p1: Vec3, ptr: Material) -> Hitable {
     Hitable::Box_(Box_::new(p0, p1, ptr))
 }
 
-pub fn random_in_unit_sphere() -> Vec3 {
-    let mut rng = thread_rng();
+pub fn new_triangle(v0: Vec3, v1: Vec3, v2: Vec3, material: Material) -> Hitable {
+    Hitable::Triangle(Triangle::new(v0, v1, v2, material))
+}
+
+pub fn new_triangle_mesh(mesh: Mesh) -> Hitable {
+    Hitable::TriangleMesh(TriangleMesh::new(mesh))
+}
+
+pub fn random_in_unit_sphere<R: Rng>(rng: &mut R) -> Vec3 {
     let mut p;
     loop {
         p = Vec3(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()) * 2.0 - Vec3(1.0, 1.0, 1.0);
@@ -180,41 +203,62 @@ pub fn random_in_unit_sphere() -> Vec3 {
     p
 }
 
-fn new_sphere(cen: Vec3, r: f32, material: Material) -> Hitable {
+// The samplers hand out a fixed number of dimensions per decision, so these
+// map uniform numbers directly instead of rejecting points.
+pub fn sample_unit_vector(u: (f32, f32)) -> Vec3 {
+    let z = 1.0 - 2.0 * u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * f32::consts::PI * u.1;
+    Vec3(r * phi.cos(), r * phi.sin(), z)
+}
+
+// A uniform point in the unit ball from three uniform numbers: u picks the
+// direction and u_radius, also in [0, 1), the distance from the center.
+pub fn sample_unit_ball(u: (f32, f32), u_radius: f32) -> Vec3 {
+    sample_unit_vector(u) * u_radius.cbrt()
+}
+
+pub fn sample_unit_disk(u: (f32, f32)) -> (f32, f32) {
+    let r = u.0.sqrt();
+    let theta = 2.0 * f32::consts::PI * u.1;
+    (r * theta.cos(), r * theta.sin())
+}
+
+// Two unit tangents that together with the unit vector n form a right
+// handed frame (Duff et al., "Building an Orthonormal Basis, Revisited").
+pub fn orthonormal_basis(n: &Vec3) -> (Vec3, Vec3) {
+    let sign = 1.0f32.copysign(n.z());
+    let a = -1.0 / (sign + n.z());
+    let b = n.x() * n.y() * a;
+    (
+        Vec3(1.0 + sign * n.x() * n.x() * a, sign * b, -sign * n.x()),
+        Vec3(b, sign + n.y() * n.y() * a, -n.y()),
+    )
+}
+
+pub fn new_sphere(cen: Vec3, r: f32, material: Material) -> Hitable {
     Hitable::Sphere(Sphere::new(cen, r, material))
 }
 
-fn new_labertian(albedo: Texture) -> Material {
+pub fn new_labertian(albedo: Texture) -> Material {
     Material::Labertian(Labertian::new(albedo))
 }
 
-fn new_constant_texture(v: Vec3) -> Texture {
+pub fn new_constant_texture(v: Vec3) -> Texture {
     Texture::ConstantTexture(ConstantTexture::new(v))
 }
 
-fn new_labertian_image(path: &str) -> Material {
-    let path = Path::new(path);
-    let result = image::load(path);
-    let data: Vec<u8>;
-    let nx: usize;
-    let ny: usize;
-    match result {
-        image::LoadResult::ImageU8(image) => {
-            data = image.data;
-            nx = image.width;
-            ny = image.height;
-        }
-        _ => panic!("Couldn't find data"),
-    }
-    Material::Labertian(Labertian::new(Texture::ImageTexture(ImageTexture::new(
-        data,
-        nx as u32,
-        ny as u32,
-    ))))
+fn new_labertian_image(path: &str) -> Result<Material, String> {
+    let texture = ImageTexture::load(Path::new(path))?;
+    Ok(Material::Labertian(Labertian::new(Texture::ImageTexture(texture))))
 }
 
-pub fn two_perlin_spheres() -> Vec<Hitable> {
-    let pertext = Texture::NoiseTexture(NoiseTexture::new(1.5));
+pub fn two_perlin_spheres(
+    nx: u32,
+    ny: u32,
+    rng: &mut XorShiftRng,
+) -> Result<(Camera, Vec<Hitable>), String> {
+    let pertext = Texture::NoiseTexture(NoiseTexture::new(1.5, rng));
     let mut hitables: Vec<Hitable> = Vec::new();
     hitables.push(new_sphere(
         Vec3(0.0, -1000.0, 0.0),
@@ -224,7 +268,7 @@ pub fn two_perlin_spheres() -> Vec<Hitable> {
     hitables.push(new_sphere(
         Vec3(0.0, 2.0, 0.0),
         2.0,
-        new_labertian_image("/home/tokuogum/Rust/rust-ray-tracer/GreatestEarth.jpg"),
+        new_labertian_image("GreatestEarth.jpg")?,
     ));
     /*    hitables.push(new_sphere(
         Vec3(0.0, 2.0, 0.0),
@@ -232,12 +276,21 @@ pub fn two_perlin_spheres() -> Vec<Hitable> {
         new_labertian(pertext.clone()),
 ));*/
 
-    hitables
+    Ok((default_camera(nx, ny), hitables))
+}
+
+pub fn random_scene(nx: u32, ny: u32, rng: &mut XorShiftRng) -> (Camera, Vec<Hitable>) {
+    random_spheres(nx, ny, 400, rng)
 }
 
-pub fn random_scene() -> Vec<Hitable> {
-    let mut rng = thread_rng();
-    let n = 50000;
+// The random scene with about n small spheres on a square grid, many
+// thousands of them make a benchmark for the bvh builders.
+pub fn random_spheres(
+    nx: u32,
+    ny: u32,
+    n: u32,
+    rng: &mut XorShiftRng,
+) -> (Camera, Vec<Hitable>) {
     let mut world: Vec<Hitable> = Vec::new();
     let checker = Texture::CheckedTexture(CheckerTexture::new(
         Texture::ConstantTexture(ConstantTexture::new(Vec3(0.2, 0.3, 0.1))),
@@ -248,8 +301,9 @@ pub fn random_scene() -> Vec<Hitable> {
         1000.0,
         Material::Labertian(Labertian::new(checker)),
     )));
-    for a in -10..10 {
-        for b in -10..10 {
+    let half_grid = ((n as f32).sqrt() / 2.0) as i32;
+    for a in -half_grid..half_grid {
+        for b in -half_grid..half_grid {
             let choose_mat = rng.gen::<f32>();
             let center = Vec3(
                 a as f32 + 0.9 * rng.gen::<f32>(),
@@ -312,7 +366,51 @@ pub fn random_scene() -> Vec<Hitable> {
         1.0,
         Material::Metal(Metal::new(Vec3(0.7, 0.6, 0.5), 0.0)),
     )));
-    world
+    (default_camera(nx, ny), world)
+}
+
+fn default_camera(nx: u32, ny: u32) -> Camera {
+    let lookfrom = Vec3(13.0, 2.0, 3.0);
+    let lookat = Vec3(0.0, 0.0, 0.0);
+    let dist_to_focus = 10.0;
+    let aperture = 0.0;
+    Camera::new(
+        lookfrom,
+        lookat,
+        Vec3(0.0, 1.0, 0.0),
+        20.0,
+        nx as f32 / ny as f32,
+        aperture,
+        dist_to_focus,
+        0.0,
+        1.0,
+    )
+}
+
+pub const BUILTIN_SCENES: [&str; 5] = [
+    "cornell_box",
+    "simple_light",
+    "random_scene",
+    "many_spheres",
+    "two_perlin_spheres",
+];
+
+// Scenes that need files from disk fail if those aren't there.
+pub fn builtin_scene(
+    name: &str,
+    nx: u32,
+    ny: u32,
+    seed: u64,
+) -> Result<(Camera, Vec<Hitable>), String> {
+    let mut rng = seeded_rng(&[seed, SCENE_STREAM]);
+    match name {
+        "cornell_box" => Ok(cornell_box(nx, ny)),
+        "simple_light" => Ok(simple_light(nx, ny, &mut rng)),
+        "random_scene" => Ok(random_scene(nx, ny, &mut rng)),
+        "many_spheres" => Ok(random_spheres(nx, ny, 50000, &mut rng)),
+        "two_perlin_spheres" => two_perlin_spheres(nx, ny, &mut rng),
+        _ => Err(format!("unknown scene `{}`", name)),
+    }
 }
 
 #[inline]
@@ -352,7 +450,16 @@ impl Aabb {
         self._max
     }
 
-    pub fn hit(&self, r: &Ray, tmin: f32, tmax: f32) -> bool {
+    pub fn centroid(&self) -> Vec3 {
+        (self._min + self._max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self._max - self._min;
+        2.0 * (d.x() * d.y() + d.y() * d.z() + d.z() * d.x())
+    }
+
+    pub fn hit(&self, r: &Ray, mut tmin: f32, mut tmax: f32) -> bool {
         for a in 0..3 {
             let t0 = ffmin(
                 (self._min[a] - r.origin()[a]) / r.direction()[a],
@@ -362,8 +469,33 @@ impl Aabb {
                 (self._min[a] - r.origin()[a]) / r.direction()[a],
                 (self._max[a] - r.origin()[a]) / r.direction()[a],
             );
-            let tmin = ffmax(t0, tmin);
-            let tmax = ffmin(t1, tmax);
+            tmin = ffmax(t0, tmin);
+            tmax = ffmin(t1, tmax);
+            if tmax <= tmin {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Same test against a precomputed inverse direction, for traversals that
+    // check many boxes with one ray. NaNs from a zero direction component
+    // fail every comparison and leave the interval alone.
+    pub fn hit_inverse(
+        &self,
+        origin: &Vec3,
+        inv_direction: &Vec3,
+        mut tmin: f32,
+        mut tmax: f32,
+    ) -> bool {
+        for a in 0..3u8 {
+            let mut t0 = (self._min[a] - origin[a]) * inv_direction[a];
+            let mut t1 = (self._max[a] - origin[a]) * inv_direction[a];
+            if inv_direction[a] < 0.0 {
+                mem::swap(&mut t0, &mut t1);
+            }
+            tmin = ffmax(t0, tmin);
+            tmax = ffmin(t1, tmax);
             if tmax <= tmin {
                 return false;
             }